@@ -0,0 +1,56 @@
+extern crate power_monitor;
+
+use std::env;
+use std::io::{self, Write};
+use std::process;
+use power_monitor::p1::{verify_crc, ReadDatagram};
+use power_monitor::p1::anonymise::{anonymise, Options};
+use power_monitor::p1::reader::DatagramReader;
+use power_monitor::p1::telegram::Telegram;
+
+fn usage() -> ! {
+    eprintln!("usage: p1-anonymise [--keep-equipment-ids] [--keep-messages] [--counter-offset <value>] < telegrams");
+    process::exit(2);
+}
+
+fn parse_options() -> Options {
+    let mut options = Options::default();
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--keep-equipment-ids" => options.equipment_ids = false,
+            "--keep-messages" => options.messages = false,
+            "--counter-offset" => match args.next().and_then(|v| v.parse().ok()) {
+                Some(offset) => options.counter_offset = Some(offset),
+                None => usage(),
+            },
+            _ => usage(),
+        }
+    }
+    options
+}
+
+fn main() {
+    let options = parse_options();
+    let stdin = io::stdin();
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+
+    for datagram in DatagramReader::new(stdin.lock()).map(verify_crc) {
+        let datagram = match datagram {
+            ReadDatagram::Datagram(datagram) => datagram,
+            _ => {
+                eprintln!("skipping a datagram that is incomplete or has an invalid CRC");
+                continue;
+            },
+        };
+        let (telegram, diagnostics) = Telegram::parse(&datagram);
+        for diagnostic in diagnostics {
+            eprintln!("dropping line {}: {:?}", diagnostic.line, diagnostic.reason);
+        }
+        let anonymised = anonymise(&telegram, &options).to_bytes_with_line_end();
+        if stdout.write_all(&anonymised).is_err() {
+            process::exit(1);
+        }
+    }
+}
@@ -1,6 +1,21 @@
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+
+#[cfg(any(feature = "std", test))]
+extern crate core;
 #[macro_use]
 extern crate nom;
-extern crate crc;
+#[cfg(feature = "tokio")]
+extern crate bytes;
+#[cfg(feature = "archive")]
+extern crate flate2;
+#[cfg(feature = "tokio")]
+extern crate futures_core;
+#[cfg(any(feature = "serial", feature = "tcp"))]
+extern crate libc;
+#[cfg(feature = "tokio")]
+extern crate tokio;
+#[cfg(feature = "tokio")]
+extern crate tokio_util;
 
 pub mod obis;
 pub mod p1;
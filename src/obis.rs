@@ -1,8 +1,10 @@
-use std::ops::{AddAssign, MulAssign};
-use std::fmt;
+use core::fmt;
 use nom::is_digit;
 
-#[derive(Debug,PartialEq)]
+// The length of "255-255:255.255.255.255".
+const MAX_LENGTH: usize = 23;
+
+#[derive(Debug,Clone,PartialEq)]
 pub struct ObisIdentifier {
     a: Option<u8>,
     b: Option<u8>,
@@ -12,19 +14,12 @@ pub struct ObisIdentifier {
     f: u8,
 }
 
-fn buf_to_int<T>(s: &[u8]) -> T
-where
-	T: AddAssign + MulAssign + From<u8>,
-{
-	let mut sum = T::from(0);
-	for digit in s {
-		sum *= T::from(10);
-		sum += T::from(*digit - b'0');
-	}
-	sum
+// Value groups above 255 do not fit, and make the identifier invalid.
+fn buf_to_u8(s: &[u8]) -> Option<u8> {
+	s.iter().try_fold(0u8, |sum, digit| sum.checked_mul(10)?.checked_add(*digit - b'0'))
 }
 
-named!(value_group <&[u8], u8>, map!(take_while_m_n!(1, 3, is_digit), buf_to_int));
+named_attr!(#[allow(clippy::manual_range_contains)], value_group <&[u8], u8>, map_opt!(take_while_m_n!(1, 3, is_digit), buf_to_u8));
 named!(value_group_a_delimiter, tag!("-"));
 named!(value_group_a <&[u8], u8>, do_parse!(
 	value: value_group >>
@@ -60,13 +55,30 @@ named!(pub obis_identifier <&[u8], ObisIdentifier>, do_parse!(
 
 impl ObisIdentifier {
 	pub fn parse(id: &str) -> Option<ObisIdentifier> {
-		match obis_identifier(id.as_bytes()) {
-			Ok((_, id)) => Some(id),
-			Err(_) => None,
+		// The parsers work on streaming input, so terminate the identifier to
+		// tell them no more digits follow.
+		let mut input = [0u8; MAX_LENGTH + 1];
+		if id.len() > MAX_LENGTH {
+			return None;
+		}
+		input[..id.len()].copy_from_slice(id.as_bytes());
+		input[id.len()] = b'(';
+		match obis_identifier(&input[..id.len() + 1]) {
+			Ok((rest, id)) if rest == b"(" => Some(id),
+			_ => None,
 		}
 	}
 }
 
+impl ObisIdentifier {
+	pub fn a(&self) -> Option<u8> { self.a }
+	pub fn b(&self) -> Option<u8> { self.b }
+	pub fn c(&self) -> u8 { self.c }
+	pub fn d(&self) -> u8 { self.d }
+	pub fn e(&self) -> u8 { self.e }
+	pub fn f(&self) -> u8 { self.f }
+}
+
 impl fmt::Display for ObisIdentifier {
 	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
 		if let Some(a) = self.a {
@@ -137,4 +149,17 @@ mod tests {
     	assert_eq!(id, ObisIdentifier { a: Some(1), b: Some(0), c: 96, d: 7, e: 21, f: 255 });
     }
 
+    #[test]
+    fn it_should_not_parse_a_string_longer_than_an_identifier() {
+    	assert_eq!(ObisIdentifier::parse("255-255:255.255.255.255"), Some(ObisIdentifier { a: Some(255), b: Some(255), c: 255, d: 255, e: 255, f: 255 }));
+    	assert_eq!(ObisIdentifier::parse("255-255:255.255.255.2550"), None);
+    }
+
+    #[test]
+    fn it_should_not_parse_a_value_group_above_255() {
+    	assert_eq!(ObisIdentifier::parse("1-0:999.8.1"), None);
+    	assert_eq!(ObisIdentifier::parse("256-0:1.8.1"), None);
+    	assert_eq!(ObisIdentifier::parse("1-0:1.8.1.256"), None);
+    }
+
 }
@@ -0,0 +1,146 @@
+use super::telegram::{CosemObject, Telegram, Value};
+
+const COUNTER_UNITS: [&str; 3] = ["kWh", "m3", "GJ"];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Options {
+    pub equipment_ids: bool,
+    pub messages: bool,
+    pub counter_offset: Option<f64>,
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            equipment_ids: true,
+            messages: true,
+            counter_offset: None,
+        }
+    }
+}
+
+pub fn anonymise(telegram: &Telegram, options: &Options) -> Telegram {
+    Telegram {
+        identification: telegram.identification.clone(),
+        objects: telegram.objects.iter().map(|o| anonymise_object(o, options)).collect(),
+    }
+}
+
+fn anonymise_object(object: &CosemObject, options: &Options) -> CosemObject {
+    let mut object = object.clone();
+    if options.equipment_ids && is_equipment_id(&object) {
+        for value in &mut object.values {
+            value.text = scrub_hex_text(&value.text);
+        }
+    } else if options.messages && is_message(&object) {
+        for value in &mut object.values {
+            value.text.clear();
+        }
+    } else if let Some(offset) = options.counter_offset {
+        for value in &mut object.values {
+            if is_counter(value) {
+                if let Some(text) = shift_counter(&value.text, offset) {
+                    value.text = text;
+                }
+            }
+        }
+    }
+    object
+}
+
+fn is_equipment_id(object: &CosemObject) -> bool {
+    let id = &object.id;
+    id.c() == 96 && id.d() == 1 && (id.e() == 0 || id.e() == 1)
+}
+
+fn is_message(object: &CosemObject) -> bool {
+    let id = &object.id;
+    id.c() == 96 && id.d() == 13
+}
+
+fn is_counter(value: &Value) -> bool {
+    match value.unit {
+        Some(ref unit) => COUNTER_UNITS.contains(&unit.as_str()),
+        None => false,
+    }
+}
+
+// Equipment identifiers are hex encoded ASCII, so replace them with the same
+// number of encoded '0' characters.
+fn scrub_hex_text(text: &str) -> String {
+    let mut scrubbed = "30".repeat(text.len() / 2);
+    if text.len() % 2 == 1 {
+        scrubbed.push('0');
+    }
+    scrubbed
+}
+
+// Shifts a fixed point counter while keeping its number of digits, wrapping
+// around like the meter itself does.
+fn shift_counter(text: &str, offset: f64) -> Option<String> {
+    let decimals = text.find('.').map(|dot| text.len() - dot - 1).unwrap_or(0);
+    let digits: String = text.chars().filter(|c| *c != '.').collect();
+    if digits.is_empty() || !digits.chars().all(|c| c.is_ascii_digit()) || digits.len() > 18 {
+        return None;
+    }
+    let modulus = 10i64.pow(digits.len() as u32);
+    let value: i64 = digits.parse().ok()?;
+    let offset = (offset * 10f64.powi(decimals as i32)).round() as i64;
+    let shifted = format!("{:0width$}", (value + offset).rem_euclid(modulus), width = digits.len());
+    if decimals == 0 {
+        Some(shifted)
+    } else {
+        let (integer, fraction) = shifted.split_at(shifted.len() - decimals);
+        Some(format!("{}.{}", integer, fraction))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::{verify_crc, ReadDatagram};
+    use obis::ObisIdentifier;
+
+    fn value_of(telegram: &Telegram, id: &str, index: usize) -> String {
+        let id = ObisIdentifier::parse(id).unwrap();
+        telegram.object(&id).unwrap().values[index].text.clone()
+    }
+
+    #[test]
+    fn it_should_scrub_equipment_ids_and_messages() {
+        let correct_datagram_1: &[u8] = include_bytes!("correct_datagram_1.test");
+        let (telegram, _) = Telegram::parse(correct_datagram_1);
+
+        let anonymised = anonymise(&telegram, &Options::default());
+
+        assert_eq!(value_of(&anonymised, "0-0:96.1.1", 0), "30303030303030303030303030303030");
+        assert_eq!(value_of(&anonymised, "0-1:96.1.0", 0), "3030303030303030303030303030303030");
+        assert_eq!(value_of(&anonymised, "0-0:96.13.0", 0), "");
+        assert_eq!(value_of(&anonymised, "1-0:1.8.1", 0), "123456.789");
+    }
+
+    #[test]
+    fn it_should_shift_counters_by_a_consistent_offset() {
+        let correct_datagram_1: &[u8] = include_bytes!("correct_datagram_1.test");
+        let (telegram, _) = Telegram::parse(correct_datagram_1);
+        let options = Options { counter_offset: Some(-123000.5), ..Options::default() };
+
+        let anonymised = anonymise(&telegram, &options);
+
+        assert_eq!(value_of(&anonymised, "1-0:1.8.1", 0), "000456.289");
+        assert_eq!(value_of(&anonymised, "1-0:2.8.2", 0), "000456.289");
+        assert_eq!(value_of(&anonymised, "0-1:24.2.1", 1), "89784.623");
+        assert_eq!(value_of(&anonymised, "0-1:24.2.1", 0), "101209112500W");
+        assert_eq!(value_of(&anonymised, "1-0:1.7.0", 0), "01.193");
+    }
+
+    #[test]
+    fn it_should_produce_a_datagram_with_a_valid_crc() {
+        let correct_datagram_1: &[u8] = include_bytes!("correct_datagram_1.test");
+        let (telegram, _) = Telegram::parse(correct_datagram_1);
+
+        let datagram = anonymise(&telegram, &Options::default()).to_bytes().into_boxed_slice();
+
+        assert_eq!(verify_crc(ReadDatagram::Datagram(datagram.clone())), ReadDatagram::Datagram(datagram));
+    }
+}
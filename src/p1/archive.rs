@@ -0,0 +1,641 @@
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use flate2::Compression;
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+
+// An archive starts with this header, followed by blocks. Each block has an
+// uncompressed header with the length of its compressed entries as a u32,
+// the times of its first and last telegram in microseconds since the Unix
+// epoch as u64 and the number of telegrams as a u32, all little endian. The
+// entries are zlib compressed, so damage is detected by the checksum.
+//
+// Every entry is the time since the previous entry as a varint, the number of
+// lines in the telegram and the operations that rebuild those lines from the
+// lines of the previous telegram in the block. The first telegram of a block
+// is stored in full, so each block can be read on its own.
+const MAGIC: &[u8; 8] = b"P1ARC\0\0\x01";
+const BLOCK_HEADER_SIZE: usize = 24;
+const DEFAULT_BLOCK_SIZE: u32 = 3600;
+const MAX_BLOCK_SIZE: usize = 256 * 1024 * 1024;
+
+// Operations, in the low two bits of a varint with their argument above them.
+// Copy takes lines from the previous telegram at the cursor, Seek moves the
+// cursor, Edit replaces the line at the cursor after a common prefix, and
+// Literal inserts a line without moving the cursor.
+const COPY: u64 = 0;
+const SEEK: u64 = 1;
+const EDIT: u64 = 2;
+const LITERAL: u64 = 3;
+
+// Writes telegrams in blocks of block_size telegrams. A block is written when
+// it is full, on flush and when the writer is dropped; until then its
+// telegrams are only kept in memory.
+pub struct ArchiveWriter<W: Write> {
+    // Only taken by finish.
+    writer: Option<W>,
+    block_size: u32,
+    entries: Vec<u8>,
+    count: u32,
+    first_time: u64,
+    last_time: Option<u64>,
+    previous: Vec<u8>,
+}
+
+impl<W: Write> ArchiveWriter<W> {
+    // Starts a new archive by writing the header.
+    pub fn new(mut writer: W) -> io::Result<ArchiveWriter<W>> {
+        writer.write_all(MAGIC)?;
+        Ok(ArchiveWriter::resume(writer, None))
+    }
+
+    fn resume(writer: W, last_time: Option<u64>) -> ArchiveWriter<W> {
+        ArchiveWriter {
+            writer: Some(writer),
+            block_size: DEFAULT_BLOCK_SIZE,
+            entries: Vec::new(),
+            count: 0,
+            first_time: 0,
+            last_time,
+            previous: Vec::new(),
+        }
+    }
+
+    // Larger blocks compress better, smaller blocks seek faster and lose
+    // less when the process dies. The default is an hour of DSMR 5 telegrams.
+    pub fn block_size(mut self, block_size: u32) -> ArchiveWriter<W> {
+        assert!(block_size > 0, "block size must be at least 1");
+        self.block_size = block_size;
+        self
+    }
+
+    // Times must not go backwards, so seeking can rely on their order.
+    pub fn write(&mut self, time: SystemTime, telegram: &[u8]) -> io::Result<()> {
+        let time = micros(time);
+        if self.last_time.is_some_and(|last| time < last) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "telegram is older than the one before it"));
+        }
+        let delta = match self.last_time {
+            Some(last) if self.count > 0 => time - last,
+            _ => {
+                self.first_time = time;
+                self.previous.clear();
+                0
+            },
+        };
+        put_varint(&mut self.entries, delta);
+        encode_delta(&mut self.entries, &self.previous, telegram);
+        self.previous.clear();
+        self.previous.extend_from_slice(telegram);
+        self.last_time = Some(time);
+        self.count += 1;
+        if self.count == self.block_size {
+            self.write_block()?;
+        }
+        Ok(())
+    }
+
+    // Writes the current block, even when it is not full.
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.write_block()?;
+        match self.writer {
+            Some(ref mut writer) => writer.flush(),
+            None => Ok(()),
+        }
+    }
+
+    pub fn finish(mut self) -> io::Result<W> {
+        self.flush()?;
+        Ok(self.writer.take().expect("writer is only taken by finish"))
+    }
+
+    fn write_block(&mut self) -> io::Result<()> {
+        if self.count == 0 {
+            return Ok(());
+        }
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::best());
+        encoder.write_all(&self.entries)?;
+        let compressed = encoder.finish()?;
+        let mut block = Vec::with_capacity(BLOCK_HEADER_SIZE + compressed.len());
+        block.extend_from_slice(&(compressed.len() as u32).to_le_bytes());
+        block.extend_from_slice(&self.first_time.to_le_bytes());
+        block.extend_from_slice(&self.last_time.unwrap_or(self.first_time).to_le_bytes());
+        block.extend_from_slice(&self.count.to_le_bytes());
+        block.extend_from_slice(&compressed);
+        if let Some(ref mut writer) = self.writer {
+            writer.write_all(&block)?;
+        }
+        self.entries.clear();
+        self.count = 0;
+        Ok(())
+    }
+}
+
+impl ArchiveWriter<File> {
+    // Opens an archive file for appending new blocks, and writes the header
+    // when the file is new or empty. A block or header cut off by a crash at
+    // the end of the file is removed first, as blocks written after it could
+    // not be read. Any other damaged block header is reported as InvalidData,
+    // and the file is left as it is.
+    pub fn append<P: AsRef<Path>>(path: P) -> io::Result<ArchiveWriter<File>> {
+        let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path)?;
+        let length = file.metadata()?.len();
+        if length < MAGIC.len() as u64 {
+            let mut header = Vec::new();
+            file.read_to_end(&mut header)?;
+            if !MAGIC.starts_with(&header) {
+                return Err(invalid("not a P1 archive"));
+            }
+            file.set_len(0)?;
+            file.seek(SeekFrom::Start(0))?;
+            return ArchiveWriter::new(file);
+        }
+        read_header(&mut file)?;
+        let (mut last_block, mut last_time) = (None, None);
+        let mut end = MAGIC.len() as u64;
+        loop {
+            let header = match read_block_header(&mut file) {
+                Ok(Some(header)) => header,
+                Ok(None) => break,
+                Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(e),
+            };
+            let block_end = end + (BLOCK_HEADER_SIZE + header.length) as u64;
+            if block_end > length {
+                break;
+            }
+            if header.length > MAX_BLOCK_SIZE || header.count == 0 {
+                return Err(invalid("malformed block"));
+            }
+            file.seek(SeekFrom::Start(block_end))?;
+            if let Some((_, previous)) = last_block.replace((end, header)) {
+                last_time = Some(previous.last_time);
+            }
+            end = block_end;
+        }
+        // The header of the last block can be complete while its entries are
+        // not, so it is decoded to be sure.
+        if let Some((start, header)) = last_block {
+            file.seek(SeekFrom::Start(start + BLOCK_HEADER_SIZE as u64))?;
+            let mut compressed = vec!(0u8; header.length);
+            file.read_exact(&mut compressed)?;
+            let time = header.last_time;
+            match check_block(&compressed, header) {
+                Ok(()) => last_time = Some(time),
+                Err(_) => end = start,
+            }
+        }
+        file.set_len(end)?;
+        file.seek(SeekFrom::Start(end))?;
+        Ok(ArchiveWriter::resume(file, last_time))
+    }
+}
+
+// Decodes every telegram of a block, which fails when the block was cut off
+// or damaged.
+fn check_block(compressed: &[u8], header: BlockHeader) -> io::Result<()> {
+    let count = header.count;
+    let mut reader = ArchiveReader { reader: compressed, entries: Vec::new(), offset: 0, remaining: 0, time: 0, previous: Vec::new() };
+    reader.read_block(header)?;
+    for _ in 0..count {
+        reader.read_telegram()?;
+    }
+    if reader.offset != reader.entries.len() {
+        return Err(invalid("malformed block"));
+    }
+    Ok(())
+}
+
+impl<W: Write> Drop for ArchiveWriter<W> {
+    fn drop(&mut self) {
+        if self.writer.is_some() {
+            let _ = self.flush();
+        }
+    }
+}
+
+struct BlockHeader {
+    length: usize,
+    first_time: u64,
+    last_time: u64,
+    count: u32,
+}
+
+pub struct ArchiveReader<R> {
+    reader: R,
+    entries: Vec<u8>,
+    offset: usize,
+    remaining: u32,
+    time: u64,
+    previous: Vec<u8>,
+}
+
+impl<R: Read> ArchiveReader<R> {
+    pub fn new(mut reader: R) -> io::Result<ArchiveReader<R>> {
+        read_header(&mut reader)?;
+        Ok(ArchiveReader { reader, entries: Vec::new(), offset: 0, remaining: 0, time: 0, previous: Vec::new() })
+    }
+
+    // Returns the next telegram with its time, byte for byte as it was
+    // written, or None at the end of the archive.
+    pub fn read_telegram(&mut self) -> io::Result<Option<(SystemTime, Vec<u8>)>> {
+        if self.remaining == 0 {
+            let header = match read_block_header(&mut self.reader)? {
+                Some(header) => header,
+                None => return Ok(None),
+            };
+            self.read_block(header)?;
+        }
+        let mut entries = &self.entries[self.offset..];
+        let length = entries.len();
+        self.time += get_varint(&mut entries)?;
+        let telegram = decode_delta(&mut entries, &self.previous)?;
+        self.offset += length - entries.len();
+        self.remaining -= 1;
+        self.previous.clear();
+        self.previous.extend_from_slice(&telegram);
+        Ok(Some((from_micros(self.time), telegram)))
+    }
+
+    // The time of the next telegram in the current block.
+    fn next_time(&self) -> io::Result<u64> {
+        Ok(self.time + get_varint(&mut &self.entries[self.offset..])?)
+    }
+
+    fn read_block(&mut self, header: BlockHeader) -> io::Result<()> {
+        if header.length > MAX_BLOCK_SIZE || header.count == 0 {
+            return Err(invalid("malformed block"));
+        }
+        let mut compressed = vec!(0u8; header.length);
+        self.reader.read_exact(&mut compressed)?;
+        self.entries.clear();
+        ZlibDecoder::new(&compressed[..]).take(MAX_BLOCK_SIZE as u64).read_to_end(&mut self.entries)?;
+        self.offset = 0;
+        self.remaining = header.count;
+        self.time = header.first_time;
+        self.previous.clear();
+        Ok(())
+    }
+}
+
+impl<R: Read + Seek> ArchiveReader<R> {
+    // Positions the reader at the first telegram at or after the given time.
+    // Only the block that holds it is decompressed.
+    pub fn seek(&mut self, time: SystemTime) -> io::Result<()> {
+        let time = micros(time);
+        self.reader.seek(SeekFrom::Start(MAGIC.len() as u64))?;
+        self.remaining = 0;
+        while let Some(header) = read_block_header(&mut self.reader)? {
+            if header.last_time < time {
+                self.reader.seek(SeekFrom::Current(header.length as i64))?;
+                continue;
+            }
+            self.read_block(header)?;
+            // Telegrams can only be rebuilt in order, so the ones before the
+            // wanted time are decoded and dropped.
+            while self.remaining > 0 && self.next_time()? < time {
+                self.read_telegram()?;
+            }
+            return Ok(());
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read> Iterator for ArchiveReader<R> {
+    type Item = io::Result<(SystemTime, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_telegram().transpose()
+    }
+}
+
+// Splits a telegram into lines that keep their line ends, so joining them
+// gives back the original bytes.
+fn lines(telegram: &[u8]) -> Vec<&[u8]> {
+    telegram.split_inclusive(|b| *b == b'\n').collect()
+}
+
+// Lines are matched on their OBIS reference, or on their first byte when
+// they have none, like the header and the CRC line.
+fn key(line: &[u8]) -> &[u8] {
+    match line.iter().position(|b| *b == b'(') {
+        Some(i) => &line[..i],
+        None => &line[..line.len().min(1)],
+    }
+}
+
+fn encode_delta(entries: &mut Vec<u8>, previous: &[u8], telegram: &[u8]) {
+    let previous = lines(previous);
+    let current = lines(telegram);
+    let mut keys = HashMap::new();
+    for (index, line) in previous.iter().enumerate() {
+        keys.entry(key(line)).or_insert(index);
+    }
+    put_varint(entries, current.len() as u64);
+    let mut cursor = 0;
+    let mut copies = 0;
+    for line in current {
+        if previous.get(cursor) == Some(&line) {
+            copies += 1;
+            cursor += 1;
+            continue;
+        }
+        if copies > 0 {
+            put_varint(entries, copies << 2 | COPY);
+            copies = 0;
+        }
+        let matching = match previous.get(cursor) {
+            Some(p) if key(p) == key(line) => Some(cursor),
+            _ => keys.get(key(line)).cloned(),
+        };
+        match matching {
+            Some(index) => {
+                if index != cursor {
+                    put_varint(entries, (index as u64) << 2 | SEEK);
+                }
+                let prefix = previous[index].iter().zip(line.iter()).take_while(|(a, b)| a == b).count();
+                put_varint(entries, (prefix as u64) << 2 | EDIT);
+                put_bytes(entries, &line[prefix..]);
+                cursor = index + 1;
+            },
+            None => {
+                put_varint(entries, (line.len() as u64) << 2 | LITERAL);
+                entries.extend_from_slice(line);
+            },
+        }
+    }
+    if copies > 0 {
+        put_varint(entries, copies << 2 | COPY);
+    }
+}
+
+fn decode_delta(entries: &mut &[u8], previous: &[u8]) -> io::Result<Vec<u8>> {
+    let previous = lines(previous);
+    let line_count = get_varint(entries)?;
+    let mut telegram = Vec::with_capacity(previous.iter().map(|l| l.len()).sum());
+    let mut cursor = 0;
+    let mut lines = 0;
+    while lines < line_count {
+        let operation = get_varint(entries)?;
+        let argument = (operation >> 2) as usize;
+        match operation & 0x03 {
+            COPY => {
+                let copied = previous.get(cursor..cursor + argument).ok_or_else(|| invalid("copy beyond the previous telegram"))?;
+                for line in copied {
+                    telegram.extend_from_slice(line);
+                }
+                cursor += argument;
+                lines += argument as u64;
+            },
+            SEEK => cursor = argument,
+            EDIT => {
+                let line = previous.get(cursor).filter(|l| l.len() >= argument).ok_or_else(|| invalid("edit beyond the previous telegram"))?;
+                telegram.extend_from_slice(&line[..argument]);
+                telegram.extend_from_slice(get_bytes(entries)?);
+                cursor += 1;
+                lines += 1;
+            },
+            _ => {
+                telegram.extend_from_slice(take(entries, argument)?);
+                lines += 1;
+            },
+        }
+    }
+    Ok(telegram)
+}
+
+fn put_varint(buffer: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buffer.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buffer.push(value as u8);
+}
+
+fn get_varint(bytes: &mut &[u8]) -> io::Result<u64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let byte = take(bytes, 1)?[0];
+        value |= ((byte & 0x7F) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(invalid("varint too long"))
+}
+
+fn put_bytes(buffer: &mut Vec<u8>, bytes: &[u8]) {
+    put_varint(buffer, bytes.len() as u64);
+    buffer.extend_from_slice(bytes);
+}
+
+fn get_bytes<'a>(bytes: &mut &'a [u8]) -> io::Result<&'a [u8]> {
+    let length = get_varint(bytes)? as usize;
+    take(bytes, length)
+}
+
+fn take<'a>(bytes: &mut &'a [u8], length: usize) -> io::Result<&'a [u8]> {
+    if length > bytes.len() {
+        return Err(invalid("entry ends early"));
+    }
+    let (taken, rest) = bytes.split_at(length);
+    *bytes = rest;
+    Ok(taken)
+}
+
+fn read_header<R: Read>(reader: &mut R) -> io::Result<()> {
+    let mut magic = [0u8; 8];
+    reader.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(invalid("not a P1 archive"));
+    }
+    Ok(())
+}
+
+fn read_block_header<R: Read>(reader: &mut R) -> io::Result<Option<BlockHeader>> {
+    let mut header = [0u8; BLOCK_HEADER_SIZE];
+    match reader.read(&mut header[..1])? {
+        0 => return Ok(None),
+        _ => reader.read_exact(&mut header[1..])?,
+    }
+    let u64_at = |offset: usize| {
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(&header[offset..offset + 8]);
+        u64::from_le_bytes(bytes)
+    };
+    Ok(Some(BlockHeader {
+        length: u32::from_le_bytes([header[0], header[1], header[2], header[3]]) as usize,
+        first_time: u64_at(4),
+        last_time: u64_at(12),
+        count: u32::from_le_bytes([header[20], header[21], header[22], header[23]]),
+    }))
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_owned())
+}
+
+fn micros(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map(|d| d.as_micros() as u64).unwrap_or(0)
+}
+
+fn from_micros(micros: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_micros(micros)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use obis::ObisIdentifier;
+    use super::super::{verify_crc, ReadDatagram};
+    use super::super::telegram::Telegram;
+
+    fn at(second: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(1_500_000_000 + second)
+    }
+
+    // DSMR 5 telegrams, one per second, with a text message that comes and
+    // goes. The message lines leave the CRC stale.
+    fn telegrams(count: u64) -> Vec<Vec<u8>> {
+        let correct_datagram_2: &[u8] = include_bytes!("correct_datagram_2.test");
+        let (mut telegram, _) = Telegram::parse(correct_datagram_2);
+        let index = |id: &str| telegram.objects.iter().position(|o| o.id == ObisIdentifier::parse(id).unwrap()).unwrap();
+        let (timestamp, counter, power) = (index("0-0:1.0.0"), index("1-0:1.8.1"), index("1-0:1.7.0"));
+        (0..count).map(|second| {
+            telegram.objects[timestamp].values[0].text = format!("1704151409{:02}S", second % 60);
+            telegram.objects[counter].values[0].text = format!("{:010.3}", 524.637 + second as f64 * 0.0003);
+            telegram.objects[power].values[0].text = format!("{:06.3}", (second % 7) as f64 * 0.1);
+            let mut bytes = telegram.to_bytes();
+            if second % 10 == 3 {
+                let message = b"0-0:96.13.0(48656C6C6F)\r\n";
+                let position = bytes.windows(4).position(|w| w == b"1-3:").unwrap();
+                bytes.splice(position..position, message.iter().cloned());
+            }
+            bytes
+        }).collect()
+    }
+
+    fn archive(telegrams: &[Vec<u8>], block_size: u32) -> Vec<u8> {
+        let mut writer = ArchiveWriter::new(Vec::new()).unwrap().block_size(block_size);
+        for (second, telegram) in telegrams.iter().enumerate() {
+            writer.write(at(second as u64), telegram).unwrap();
+        }
+        writer.finish().unwrap()
+    }
+
+    #[test]
+    fn it_should_reconstruct_telegrams_byte_for_byte() {
+        let telegrams = telegrams(300);
+        let archive = archive(&telegrams, 64);
+
+        let read: Vec<(SystemTime, Vec<u8>)> = ArchiveReader::new(&archive[..]).unwrap().map(|t| t.unwrap()).collect();
+
+        assert_eq!(read.len(), telegrams.len());
+        for (second, (time, telegram)) in read.into_iter().enumerate() {
+            assert_eq!(time, at(second as u64));
+            assert_eq!(telegram, telegrams[second]);
+            if second % 10 != 3 {
+                let datagram = verify_crc(ReadDatagram::Datagram(telegram.into_boxed_slice()));
+                assert!(matches!(datagram, ReadDatagram::Datagram(_)));
+            }
+        }
+        let raw_size: usize = telegrams.iter().map(|t| t.len()).sum();
+        assert!(archive.len() * 50 < raw_size, "{} bytes for {} raw bytes", archive.len(), raw_size);
+    }
+
+    #[test]
+    fn it_should_seek_to_a_point_in_time() {
+        let telegrams = telegrams(300);
+        let archive = archive(&telegrams, 64);
+        let mut reader = ArchiveReader::new(io::Cursor::new(archive)).unwrap();
+
+        reader.seek(at(150)).unwrap();
+        assert_eq!(reader.next().unwrap().unwrap(), (at(150), telegrams[150].clone()));
+        assert_eq!(reader.next().unwrap().unwrap(), (at(151), telegrams[151].clone()));
+
+        reader.seek(at(0) - Duration::from_secs(1)).unwrap();
+        assert_eq!(reader.next().unwrap().unwrap(), (at(0), telegrams[0].clone()));
+
+        reader.seek(at(299) + Duration::from_millis(1)).unwrap();
+        assert!(reader.next().is_none());
+    }
+
+    #[test]
+    fn it_should_append_blocks_to_an_existing_archive_file() {
+        let path = std::env::temp_dir().join(format!("p1-archive-test-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let telegrams = telegrams(20);
+
+        {
+            let mut writer = ArchiveWriter::append(&path).unwrap();
+            for second in 0..10 {
+                writer.write(at(second), &telegrams[second as usize]).unwrap();
+            }
+        }
+        let mut writer = ArchiveWriter::append(&path).unwrap();
+        assert_eq!(writer.write(at(5), &telegrams[5]).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        for second in 10..20 {
+            writer.write(at(second), &telegrams[second as usize]).unwrap();
+        }
+        writer.finish().unwrap();
+        let read: Vec<Vec<u8>> = ArchiveReader::new(File::open(&path).unwrap()).unwrap().map(|t| t.unwrap().1).collect();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(read, telegrams);
+    }
+
+    #[test]
+    fn it_should_remove_a_block_cut_off_by_a_crash_before_appending() {
+        let path = std::env::temp_dir().join(format!("p1-archive-crash-test-{}", std::process::id()));
+        let telegrams = telegrams(20);
+        let complete = archive(&telegrams[..15], 5);
+        let full = archive(&telegrams, 5);
+        let mut damaged = full.clone();
+        damaged[full.len() - 1] ^= 0x40;
+        let cut_off = vec!(
+            full[..complete.len() + 10].to_vec(),
+            full[..full.len() - 10].to_vec(),
+            damaged,
+        );
+
+        for archive in cut_off {
+            std::fs::write(&path, &archive).unwrap();
+            let mut writer = ArchiveWriter::append(&path).unwrap().block_size(5);
+            for second in 15..20 {
+                writer.write(at(second), &telegrams[second as usize]).unwrap();
+            }
+            writer.finish().unwrap();
+            let read: Vec<Vec<u8>> = ArchiveReader::new(File::open(&path).unwrap()).unwrap().map(|t| t.unwrap().1).collect();
+            assert_eq!(read, telegrams);
+        }
+
+        // A damaged header in the middle is not a crash, so nothing is removed.
+        let mut damaged = full.clone();
+        let second = archive(&telegrams[..5], 5).len();
+        damaged[second + 20..second + 24].copy_from_slice(&0u32.to_le_bytes());
+        std::fs::write(&path, &damaged).unwrap();
+        assert_eq!(ArchiveWriter::append(&path).err().map(|e| e.kind()), Some(io::ErrorKind::InvalidData));
+        assert_eq!(std::fs::read(&path).unwrap(), damaged);
+
+        std::fs::write(&path, &MAGIC[..3]).unwrap();
+        ArchiveWriter::append(&path).unwrap().write(at(0), &telegrams[0]).unwrap();
+        let read: Vec<Vec<u8>> = ArchiveReader::new(File::open(&path).unwrap()).unwrap().map(|t| t.unwrap().1).collect();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(read, &telegrams[..1]);
+    }
+
+    #[test]
+    fn it_should_detect_a_damaged_block() {
+        let mut archive = archive(&telegrams(10), 64);
+        let length = archive.len();
+        archive[length - 10] ^= 0x40;
+
+        let result: io::Result<Vec<(SystemTime, Vec<u8>)>> = ArchiveReader::new(&archive[..]).unwrap().collect();
+
+        assert!(result.is_err());
+    }
+}
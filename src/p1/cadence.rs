@@ -0,0 +1,307 @@
+use std::collections::VecDeque;
+use std::sync::{mpsc, Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// The number of recent intervals the cadence is learned from.
+const INTERVALS: usize = 9;
+// Used until the cadence is known, which covers DSMR 4 meters.
+const DEFAULT_STALL_THRESHOLD: Duration = Duration::from_secs(30);
+const CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CadenceEvent {
+    // Nothing arrived for longer than the stall threshold.
+    Stalled { silence: Duration },
+    // A telegram arrived after a stall. Missed counts the telegrams that
+    // should have arrived in between.
+    Resumed { silence: Duration, missed: u64 },
+}
+
+// Learns how often telegrams arrive, every second for DSMR 5 and every ten
+// seconds for DSMR 4, and counts the ones that are missing. The monitor only
+// does bookkeeping on the instants it is given; a Watchdog checks it from
+// another thread, so a stall is noticed while the reader is blocked.
+#[derive(Debug, Clone)]
+pub struct CadenceMonitor {
+    started: Instant,
+    last: Option<Instant>,
+    intervals: VecDeque<Duration>,
+    cadence: Option<Duration>,
+    stall_threshold: Option<Duration>,
+    stalled: bool,
+    telegrams: u64,
+    missed: u64,
+}
+
+impl CadenceMonitor {
+    pub fn new() -> CadenceMonitor {
+        CadenceMonitor {
+            started: Instant::now(),
+            last: None,
+            intervals: VecDeque::with_capacity(INTERVALS),
+            cadence: None,
+            stall_threshold: None,
+            stalled: false,
+            telegrams: 0,
+            missed: 0,
+        }
+    }
+
+    // By default a stall is three times the learned cadence.
+    pub fn stall_threshold(mut self, stall_threshold: Duration) -> CadenceMonitor {
+        self.stall_threshold = Some(stall_threshold);
+        self
+    }
+
+    pub fn cadence(&self) -> Option<Duration> {
+        self.cadence
+    }
+
+    pub fn telegrams(&self) -> u64 {
+        self.telegrams
+    }
+
+    pub fn missed(&self) -> u64 {
+        self.missed
+    }
+
+    pub fn is_stalled(&self) -> bool {
+        self.stalled
+    }
+
+    fn threshold(&self) -> Duration {
+        self.stall_threshold.or_else(|| self.cadence.map(|c| c * 3)).unwrap_or(DEFAULT_STALL_THRESHOLD)
+    }
+
+    // Records a telegram that arrived at the given instant, usually the
+    // receive time of its last byte.
+    pub fn record(&mut self, at: Instant) -> Option<CadenceEvent> {
+        self.telegrams += 1;
+        let (interval, missed) = match self.last.replace(at) {
+            Some(last) => self.learn(at.saturating_duration_since(last)),
+            None => (at.saturating_duration_since(self.started), 0),
+        };
+        if self.stalled {
+            self.stalled = false;
+            Some(CadenceEvent::Resumed { silence: interval, missed })
+        } else {
+            None
+        }
+    }
+
+    // Returns the interval and the number of telegrams missed in it.
+    fn learn(&mut self, interval: Duration) -> (Duration, u64) {
+        let missed = self.cadence.map(|c| missed_between(interval, c)).unwrap_or(0);
+        self.missed += missed;
+        if self.intervals.len() == INTERVALS {
+            self.intervals.pop_front();
+        }
+        self.intervals.push_back(interval);
+        // The median ignores the odd gap or burst.
+        let mut sorted: Vec<Duration> = self.intervals.iter().cloned().collect();
+        sorted.sort();
+        self.cadence = Some(sorted[sorted.len() / 2]);
+        (interval, missed)
+    }
+
+    // Reports a stall once, when nothing was recorded for longer than the
+    // threshold. Before the first telegram the silence is counted from when
+    // the monitor was created, so a meter that never sends is reported too.
+    pub fn check(&mut self, now: Instant) -> Option<CadenceEvent> {
+        let silence = now.saturating_duration_since(self.last.unwrap_or(self.started));
+        if self.stalled || silence <= self.threshold() {
+            return None;
+        }
+        self.stalled = true;
+        Some(CadenceEvent::Stalled { silence })
+    }
+}
+
+impl Default for CadenceMonitor {
+    fn default() -> CadenceMonitor {
+        CadenceMonitor::new()
+    }
+}
+
+// Telegrams arrive with some jitter, so the gap is rounded to whole cadences.
+fn missed_between(interval: Duration, cadence: Duration) -> u64 {
+    if cadence.is_zero() {
+        return 0;
+    }
+    let cadences = (interval.as_secs_f64() / cadence.as_secs_f64()).round() as u64;
+    cadences.saturating_sub(1)
+}
+
+struct Shared {
+    monitor: Mutex<CadenceMonitor>,
+    stopped: Mutex<bool>,
+    wake: Condvar,
+}
+
+// Checks a CadenceMonitor on a background thread and sends its events to a
+// channel. The reading thread calls record for every telegram; the thread
+// stops when the watchdog is dropped.
+pub struct Watchdog {
+    shared: Arc<Shared>,
+    events: mpsc::Sender<CadenceEvent>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Watchdog {
+    pub fn spawn(mut monitor: CadenceMonitor) -> (Watchdog, mpsc::Receiver<CadenceEvent>) {
+        monitor.started = Instant::now();
+        let shared = Arc::new(Shared { monitor: Mutex::new(monitor), stopped: Mutex::new(false), wake: Condvar::new() });
+        let (sender, receiver) = mpsc::channel();
+        let thread = {
+            let shared = shared.clone();
+            let sender = sender.clone();
+            thread::spawn(move || {
+                let mut stopped = shared.stopped.lock().unwrap();
+                while !*stopped {
+                    stopped = shared.wake.wait_timeout(stopped, CHECK_INTERVAL).unwrap().0;
+                    let event = shared.monitor.lock().unwrap().check(Instant::now());
+                    if let Some(event) = event {
+                        if sender.send(event).is_err() {
+                            return;
+                        }
+                    }
+                }
+            })
+        };
+        (Watchdog { shared, events: sender, thread: Some(thread) }, receiver)
+    }
+
+    pub fn record(&self, at: Instant) {
+        let event = self.shared.monitor.lock().unwrap().record(at);
+        if let Some(event) = event {
+            let _ = self.events.send(event);
+        }
+    }
+
+    // A copy of the monitor, for its cadence and counters.
+    pub fn monitor(&self) -> CadenceMonitor {
+        self.shared.monitor.lock().unwrap().clone()
+    }
+}
+
+impl Drop for Watchdog {
+    fn drop(&mut self) {
+        *self.shared.stopped.lock().unwrap() = true;
+        self.shared.wake.notify_one();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+    use super::super::ReadDatagram;
+    use super::super::reader::DatagramReader;
+
+    fn seconds(seconds: f64) -> Duration {
+        Duration::from_secs_f64(seconds)
+    }
+
+    #[test]
+    fn it_should_learn_the_cadence_and_count_missed_telegrams() {
+        let start = Instant::now();
+        let mut monitor = CadenceMonitor::new();
+
+        for at in &[0.0, 1.02, 1.98, 3.0, 4.01, 8.0, 9.0] {
+            assert_eq!(monitor.record(start + seconds(*at)), None);
+        }
+
+        assert!(monitor.cadence().unwrap() >= seconds(0.98) && monitor.cadence().unwrap() <= seconds(1.02));
+        assert_eq!(monitor.telegrams(), 7);
+        assert_eq!(monitor.missed(), 3);
+    }
+
+    #[test]
+    fn it_should_report_a_stall_once_and_the_resumption() {
+        let start = Instant::now();
+        let mut monitor = CadenceMonitor::new();
+        for at in 0..5 {
+            monitor.record(start + Duration::from_secs(10 * at));
+        }
+
+        assert_eq!(monitor.check(start + seconds(70.0)), None);
+        assert_eq!(monitor.check(start + seconds(71.0)), Some(CadenceEvent::Stalled { silence: seconds(31.0) }));
+        assert_eq!(monitor.check(start + seconds(80.0)), None);
+        assert!(monitor.is_stalled());
+        assert_eq!(monitor.record(start + seconds(90.0)), Some(CadenceEvent::Resumed { silence: seconds(50.0), missed: 4 }));
+        assert!(!monitor.is_stalled());
+    }
+
+    #[test]
+    fn it_should_report_a_stall_before_the_first_telegram() {
+        let mut monitor = CadenceMonitor::new();
+        let start = monitor.started;
+
+        assert_eq!(monitor.check(start + seconds(30.0)), None);
+        assert_eq!(monitor.check(start + seconds(31.0)), Some(CadenceEvent::Stalled { silence: seconds(31.0) }));
+        assert_eq!(monitor.record(start + seconds(40.0)), Some(CadenceEvent::Resumed { silence: seconds(40.0), missed: 0 }));
+        assert_eq!(monitor.cadence(), None);
+
+        let (_watchdog, events) = Watchdog::spawn(CadenceMonitor::new().stall_threshold(Duration::from_millis(50)));
+        match events.recv_timeout(Duration::from_secs(5)) {
+            Ok(CadenceEvent::Stalled { silence }) => assert!(silence > Duration::from_millis(50)),
+            other => panic!("expected a stall, got {:?}", other),
+        }
+    }
+
+    // Blocks after the first datagram until the test lets it continue.
+    struct BlockingReader {
+        data: Option<Vec<u8>>,
+        unblock: mpsc::Receiver<Vec<u8>>,
+    }
+
+    impl io::Read for BlockingReader {
+        fn read(&mut self, b: &mut [u8]) -> io::Result<usize> {
+            let data = match self.data.take() {
+                Some(data) => data,
+                None => match self.unblock.recv() {
+                    Ok(data) => data,
+                    Err(_) => return Ok(0),
+                },
+            };
+            b[..data.len()].copy_from_slice(&data);
+            Ok(data.len())
+        }
+    }
+
+    #[test]
+    fn it_should_detect_a_stall_while_the_reader_blocks() {
+        let correct_datagram_1: &[u8] = include_bytes!("correct_datagram_1.test");
+        let mut with_line_end = correct_datagram_1.to_vec();
+        with_line_end.extend_from_slice(b"\r\n");
+        let (unblock, blocked) = mpsc::channel();
+        let source = BlockingReader { data: Some(with_line_end.clone()), unblock: blocked };
+        let (watchdog, events) = Watchdog::spawn(CadenceMonitor::new().stall_threshold(Duration::from_millis(50)));
+
+        let reader = thread::spawn(move || {
+            let mut reader = DatagramReader::new(io::BufReader::new(source));
+            for (datagram, received) in reader.timestamped() {
+                assert!(matches!(datagram, ReadDatagram::Datagram(_)));
+                watchdog.record(received.last_byte().instant);
+            }
+            watchdog.monitor()
+        });
+
+        match events.recv_timeout(Duration::from_secs(5)) {
+            Ok(CadenceEvent::Stalled { silence }) => assert!(silence > Duration::from_millis(50)),
+            other => panic!("expected a stall, got {:?}", other),
+        }
+        unblock.send(with_line_end).unwrap();
+        drop(unblock);
+        match events.recv_timeout(Duration::from_secs(5)) {
+            Ok(CadenceEvent::Resumed { missed, .. }) => assert_eq!(missed, 0),
+            other => panic!("expected a resumption, got {:?}", other),
+        }
+        let monitor = reader.join().unwrap();
+        assert_eq!(monitor.telegrams(), 2);
+    }
+}
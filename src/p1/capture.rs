@@ -0,0 +1,536 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use super::{CrcError, ReadDatagram};
+use super::timestamp::Received;
+
+// A capture starts with this header, followed by records that each start
+// with their length as a little endian u32. After the length come the first
+// byte and CRC byte receive times in microseconds since the Unix epoch as
+// u64, with u64::MAX for a missing CRC byte, the source ID as a u16 length
+// and UTF-8 text, and the datagram. The datagram is a kind byte, the fields
+// of that kind, and the raw bytes up to the end of the record.
+const MAGIC: &[u8; 8] = b"P1CAP\0\0\x01";
+const NO_TIME: u64 = u64::MAX;
+const MAX_RECORD_SIZE: usize = 1024 * 1024;
+
+const DATAGRAM: u8 = 0;
+const INCOMPLETE_DATAGRAM: u8 = 1;
+const INVALID_CRC: u8 = 2;
+const REPAIRED: u8 = 3;
+
+#[derive(Debug, PartialEq)]
+pub struct Record {
+    pub source: String,
+    pub first_byte: SystemTime,
+    pub crc_byte: Option<SystemTime>,
+    pub datagram: ReadDatagram,
+}
+
+impl Record {
+    fn last_byte(&self) -> SystemTime {
+        self.crc_byte.unwrap_or(self.first_byte)
+    }
+}
+
+// Appends records to a capture. Each record is written with a single call,
+// so a capture cut off by a crash only loses its last record, which append
+// removes before writing new ones.
+pub struct CaptureWriter<W> {
+    writer: W,
+}
+
+impl<W: Write> CaptureWriter<W> {
+    // Starts a new capture by writing the header.
+    pub fn new(mut writer: W) -> io::Result<CaptureWriter<W>> {
+        writer.write_all(MAGIC)?;
+        Ok(CaptureWriter { writer })
+    }
+
+    pub fn write(&mut self, source: &str, datagram: &ReadDatagram, received: &Received) -> io::Result<()> {
+        self.write_record(&Record {
+            source: source.to_owned(),
+            first_byte: received.first_byte.time,
+            crc_byte: received.crc_byte.map(|t| t.time),
+            datagram: datagram.clone(),
+        })
+    }
+
+    pub fn write_record(&mut self, record: &Record) -> io::Result<()> {
+        let source = record.source.as_bytes();
+        if source.len() > u16::MAX as usize {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "source ID too long"));
+        }
+        let mut buffer = vec!(0u8; 4);
+        buffer.extend_from_slice(&micros(record.first_byte).to_le_bytes());
+        buffer.extend_from_slice(&record.crc_byte.map(micros).unwrap_or(NO_TIME).to_le_bytes());
+        buffer.extend_from_slice(&(source.len() as u16).to_le_bytes());
+        buffer.extend_from_slice(source);
+        let bytes = match record.datagram {
+            ReadDatagram::Datagram(ref datagram) => {
+                buffer.push(DATAGRAM);
+                datagram
+            },
+            ReadDatagram::IncompleteDatagram(ref datagram) => {
+                buffer.push(INCOMPLETE_DATAGRAM);
+                datagram
+            },
+            ReadDatagram::InvalidCrc { ref datagram, actual_crc, error } => {
+                buffer.push(INVALID_CRC);
+                buffer.extend_from_slice(&actual_crc.to_le_bytes());
+                let (tag, expected) = encode_crc_error(error);
+                buffer.push(tag);
+                buffer.extend_from_slice(&expected.to_le_bytes());
+                datagram
+            },
+            ReadDatagram::Repaired { ref datagram, corrected_offset } => {
+                buffer.push(REPAIRED);
+                buffer.extend_from_slice(&(corrected_offset as u32).to_le_bytes());
+                datagram
+            },
+        };
+        buffer.extend_from_slice(bytes);
+        if buffer.len() - 4 > MAX_RECORD_SIZE {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "record too large"));
+        }
+        let length = (buffer.len() - 4) as u32;
+        buffer[..4].copy_from_slice(&length.to_le_bytes());
+        self.writer.write_all(&buffer)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+impl CaptureWriter<File> {
+    // Opens a capture file for appending, and writes the header when the
+    // file is new or empty. A record or header cut off by a crash at the end
+    // of the file is removed first, as records written after it could not be
+    // read. A damaged record anywhere else is reported as InvalidData, and
+    // the file is left as it is.
+    pub fn append<P: AsRef<Path>>(path: P) -> io::Result<CaptureWriter<File>> {
+        let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path)?;
+        if file.metadata()?.len() < MAGIC.len() as u64 {
+            let mut header = Vec::new();
+            file.read_to_end(&mut header)?;
+            if !MAGIC.starts_with(&header) {
+                return Err(invalid("not a P1 capture"));
+            }
+            file.set_len(0)?;
+            file.seek(SeekFrom::Start(0))?;
+            return CaptureWriter::new(file);
+        }
+        let end = complete_length(&mut file)?;
+        file.set_len(end)?;
+        file.seek(SeekFrom::Start(end))?;
+        Ok(CaptureWriter { writer: file })
+    }
+}
+
+// Returns the length of a capture up to the end of its last complete record.
+fn complete_length(file: &mut File) -> io::Result<u64> {
+    let mut reader = io::BufReader::new(file);
+    read_header(&mut reader)?;
+    let mut end = MAGIC.len() as u64;
+    loop {
+        match read_record_body(&mut reader) {
+            Ok(Some(body)) => {
+                decode_record(&body).ok_or_else(|| invalid("malformed record"))?;
+                end += 4 + body.len() as u64;
+            },
+            Ok(None) => return Ok(end),
+            // Only the last record can run into the end of the file.
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(end),
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+// How fast a capture is replayed, judged by the receive times of the last
+// byte of each datagram.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Speed {
+    RealTime,
+    // For example 60.0 to replay an hour in a minute. It must be positive.
+    Scaled(f64),
+    AsFastAsPossible,
+}
+
+pub struct CaptureReader<R> {
+    reader: R,
+}
+
+impl<R: Read> CaptureReader<R> {
+    pub fn new(mut reader: R) -> io::Result<CaptureReader<R>> {
+        read_header(&mut reader)?;
+        Ok(CaptureReader { reader })
+    }
+
+    // Returns None at the end of the capture. A record that was cut off is
+    // reported as UnexpectedEof.
+    pub fn read_record(&mut self) -> io::Result<Option<Record>> {
+        match read_record_body(&mut self.reader)? {
+            Some(body) => decode_record(&body).map(Some).ok_or_else(|| invalid("malformed record")),
+            None => Ok(None),
+        }
+    }
+
+    // Yields the datagrams as a DatagramReader would, waiting between them
+    // according to their receive times. A scaled speed that is not positive
+    // is reported as InvalidInput.
+    pub fn replay(self, speed: Speed) -> io::Result<Replay<R>> {
+        if let Speed::Scaled(scale) = speed {
+            if !(scale > 0.0 && scale.is_finite()) {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "replay speed must be positive"));
+            }
+        }
+        Ok(Replay { capture: self, speed, start: None, error: None })
+    }
+}
+
+impl<R: Read> Iterator for CaptureReader<R> {
+    type Item = io::Result<Record>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_record().transpose()
+    }
+}
+
+pub struct Replay<R> {
+    capture: CaptureReader<R>,
+    speed: Speed,
+    start: Option<(Instant, SystemTime)>,
+    error: Option<io::Error>,
+}
+
+impl<R: Read> Replay<R> {
+    // Returns the error that ended the replay, if any.
+    pub fn take_error(&mut self) -> Option<io::Error> {
+        self.error.take()
+    }
+
+    // Yields each record when it is due, for when the source ID or the
+    // receive times are needed as well.
+    pub fn next_record(&mut self) -> io::Result<Option<Record>> {
+        let record = match self.capture.read_record()? {
+            Some(record) => record,
+            None => return Ok(None),
+        };
+        let scale = match self.speed {
+            Speed::RealTime => 1.0,
+            Speed::Scaled(scale) => scale,
+            Speed::AsFastAsPossible => return Ok(Some(record)),
+        };
+        let (start, first) = *self.start.get_or_insert((Instant::now(), record.last_byte()));
+        // Records that went back in time are replayed right away.
+        let offset = record.last_byte().duration_since(first).unwrap_or_default();
+        let due = start + offset.div_f64(scale);
+        let now = Instant::now();
+        if due > now {
+            thread::sleep(due - now);
+        }
+        Ok(Some(record))
+    }
+}
+
+impl<R: Read> Iterator for Replay<R> {
+    type Item = ReadDatagram;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.next_record() {
+            Ok(record) => record.map(|r| r.datagram),
+            Err(e) => {
+                self.error = Some(e);
+                None
+            },
+        }
+    }
+}
+
+fn read_header<R: Read>(reader: &mut R) -> io::Result<()> {
+    let mut magic = [0u8; 8];
+    reader.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(invalid("not a P1 capture"));
+    }
+    Ok(())
+}
+
+// Returns the bytes after the length of the next record, or None at the end
+// of the capture.
+fn read_record_body<R: Read>(reader: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let mut length = [0u8; 4];
+    match reader.read(&mut length[..1])? {
+        0 => return Ok(None),
+        _ => reader.read_exact(&mut length[1..])?,
+    }
+    let length = u32::from_le_bytes(length) as usize;
+    if length > MAX_RECORD_SIZE {
+        return Err(invalid("record too large"));
+    }
+    let mut body = vec!(0u8; length);
+    reader.read_exact(&mut body)?;
+    Ok(Some(body))
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_owned())
+}
+
+fn micros(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map(|d| d.as_micros() as u64).unwrap_or(0)
+}
+
+fn from_micros(micros: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_micros(micros)
+}
+
+fn encode_crc_error(error: CrcError) -> (u8, u16) {
+    match error {
+        CrcError::Missing => (0, 0),
+        CrcError::WrongLength => (1, 0),
+        CrcError::NotHex => (2, 0),
+        CrcError::Mismatch { expected } => (3, expected),
+        CrcError::Lowercase => (4, 0),
+        CrcError::Whitespace => (5, 0),
+        CrcError::MissingLineEnd => (6, 0),
+    }
+}
+
+fn decode_crc_error(tag: u8, expected: u16) -> Option<CrcError> {
+    match tag {
+        0 => Some(CrcError::Missing),
+        1 => Some(CrcError::WrongLength),
+        2 => Some(CrcError::NotHex),
+        3 => Some(CrcError::Mismatch { expected }),
+        4 => Some(CrcError::Lowercase),
+        5 => Some(CrcError::Whitespace),
+        6 => Some(CrcError::MissingLineEnd),
+        _ => None,
+    }
+}
+
+// Takes fields off the front of a record.
+struct Fields<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Fields<'a> {
+    fn take(&mut self, length: usize) -> Option<&'a [u8]> {
+        if length > self.bytes.len() {
+            return None;
+        }
+        let (field, rest) = self.bytes.split_at(length);
+        self.bytes = rest;
+        Some(field)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2).map(|b| u16::from_le_bytes([b[0], b[1]]))
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        self.take(4).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Some(u64::from_le_bytes(bytes))
+    }
+}
+
+fn decode_record(bytes: &[u8]) -> Option<Record> {
+    let mut fields = Fields { bytes };
+    let first_byte = from_micros(fields.u64()?);
+    let crc_byte = match fields.u64()? {
+        NO_TIME => None,
+        micros => Some(from_micros(micros)),
+    };
+    let source_length = fields.u16()? as usize;
+    let source = String::from_utf8(fields.take(source_length)?.to_vec()).ok()?;
+    let datagram = match fields.u8()? {
+        DATAGRAM => ReadDatagram::Datagram(fields.bytes.into()),
+        INCOMPLETE_DATAGRAM => ReadDatagram::IncompleteDatagram(fields.bytes.into()),
+        INVALID_CRC => {
+            let actual_crc = fields.u16()?;
+            let tag = fields.u8()?;
+            let error = decode_crc_error(tag, fields.u16()?)?;
+            ReadDatagram::InvalidCrc { datagram: fields.bytes.into(), actual_crc, error }
+        },
+        REPAIRED => {
+            let corrected_offset = fields.u32()? as usize;
+            ReadDatagram::Repaired { datagram: fields.bytes.into(), corrected_offset }
+        },
+        _ => return None,
+    };
+    Some(Record { source, first_byte, crc_byte, datagram })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::reader::DatagramReader;
+
+    fn at(seconds: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(1_500_000_000 + seconds)
+    }
+
+    fn records() -> Vec<Record> {
+        let correct_datagram_1: &[u8] = include_bytes!("correct_datagram_1.test");
+        let correct_datagram_2: &[u8] = include_bytes!("correct_datagram_2.test");
+        vec!(
+            Record { source: "serial:/dev/ttyUSB0".to_owned(), first_byte: at(0), crc_byte: Some(at(1)), datagram: ReadDatagram::Datagram(correct_datagram_1.into()) },
+            Record { source: "serial:/dev/ttyUSB0".to_owned(), first_byte: at(10), crc_byte: None, datagram: ReadDatagram::IncompleteDatagram(correct_datagram_2[..200].into()) },
+            Record {
+                source: "tcp:meter:2000".to_owned(),
+                first_byte: at(20),
+                crc_byte: Some(at(21)),
+                datagram: ReadDatagram::InvalidCrc { datagram: correct_datagram_2.into(), actual_crc: 0x1234, error: CrcError::Mismatch { expected: 0x6C8D } },
+            },
+            Record { source: String::new(), first_byte: at(30), crc_byte: Some(at(30)), datagram: ReadDatagram::Repaired { datagram: correct_datagram_1.into(), corrected_offset: 100 } },
+        )
+    }
+
+    fn capture(records: &[Record]) -> Vec<u8> {
+        let mut writer = CaptureWriter::new(Vec::new()).unwrap();
+        for record in records {
+            writer.write_record(record).unwrap();
+        }
+        writer.into_inner()
+    }
+
+    #[test]
+    fn it_should_read_back_every_kind_of_datagram() {
+        let records = records();
+        let capture = capture(&records);
+
+        let read: Vec<Record> = CaptureReader::new(&capture[..]).unwrap().map(|r| r.unwrap()).collect();
+
+        assert_eq!(read, records);
+    }
+
+    #[test]
+    fn it_should_replay_the_datagrams_of_a_live_source() {
+        let correct_datagram_1: &[u8] = include_bytes!("correct_datagram_1.test");
+        let correct_datagram_2: &[u8] = include_bytes!("correct_datagram_2.test");
+        let mut input = correct_datagram_1.to_vec();
+        input.extend_from_slice(b"\r\n");
+        input.extend_from_slice(&correct_datagram_2[..200]);
+        input.extend_from_slice(correct_datagram_2);
+        input.extend_from_slice(b"\r\n");
+        let mut live = DatagramReader::new(io::BufReader::new(&input[..])).verify_crc(true);
+        let mut writer = CaptureWriter::new(Vec::new()).unwrap();
+        let mut expected = Vec::new();
+        for (datagram, received) in live.timestamped() {
+            writer.write("test", &datagram, &received).unwrap();
+            expected.push(datagram);
+        }
+
+        let replayed: Vec<ReadDatagram> = CaptureReader::new(&writer.into_inner()[..]).unwrap().replay(Speed::AsFastAsPossible).unwrap().collect();
+
+        assert_eq!(expected.len(), 3);
+        assert_eq!(replayed, expected);
+    }
+
+    #[test]
+    fn it_should_replay_at_a_scaled_speed() {
+        let records = records();
+        let capture = capture(&records);
+        let start = Instant::now();
+
+        let mut replay = CaptureReader::new(&capture[..]).unwrap().replay(Speed::Scaled(1000.0)).unwrap();
+        assert_eq!(replay.by_ref().count(), 4);
+
+        // The last datagram is due 29 seconds after the first.
+        assert!(start.elapsed() >= Duration::from_millis(29));
+        assert!(replay.take_error().is_none());
+    }
+
+    #[test]
+    fn it_should_report_a_truncated_record() {
+        let capture = capture(&records());
+
+        let mut reader = CaptureReader::new(&capture[..capture.len() - 10]).unwrap();
+
+        assert_eq!(reader.by_ref().take(3).filter(|r| r.is_ok()).count(), 3);
+        assert_eq!(reader.next().unwrap().unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+        assert_eq!(CaptureReader::new(&b"P1CAP\0\0\x02"[..]).err().map(|e| e.kind()), Some(io::ErrorKind::InvalidData));
+    }
+
+    #[test]
+    fn it_should_append_to_an_existing_capture_file() {
+        let path = std::env::temp_dir().join(format!("p1-capture-test-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let records = records();
+
+        CaptureWriter::append(&path).unwrap().write_record(&records[0]).unwrap();
+        CaptureWriter::append(&path).unwrap().write_record(&records[1]).unwrap();
+        let read: Vec<Record> = CaptureReader::new(File::open(&path).unwrap()).unwrap().map(|r| r.unwrap()).collect();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(read, &records[..2]);
+    }
+
+    #[test]
+    fn it_should_remove_a_record_cut_off_by_a_crash_before_appending() {
+        let path = std::env::temp_dir().join(format!("p1-capture-crash-test-{}", std::process::id()));
+        let mut expected = records();
+        expected.remove(2);
+        let records = records();
+        let complete = capture(&records[..2]);
+        let mut cut_off = complete.clone();
+        cut_off.extend_from_slice(&capture(&records[2..3])[MAGIC.len()..][..50]);
+
+        std::fs::write(&path, &cut_off).unwrap();
+        CaptureWriter::append(&path).unwrap().write_record(&records[3]).unwrap();
+        let read: Vec<Record> = CaptureReader::new(File::open(&path).unwrap()).unwrap().map(|r| r.unwrap()).collect();
+        assert_eq!(read, expected);
+
+        std::fs::write(&path, &MAGIC[..3]).unwrap();
+        CaptureWriter::append(&path).unwrap().write_record(&records[0]).unwrap();
+        let read: Vec<Record> = CaptureReader::new(File::open(&path).unwrap()).unwrap().map(|r| r.unwrap()).collect();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(read, &records[..1]);
+    }
+
+    #[test]
+    fn it_should_not_append_to_a_capture_with_a_damaged_record() {
+        let path = std::env::temp_dir().join(format!("p1-capture-damaged-test-{}", std::process::id()));
+        let records = records();
+        let mut damaged = capture(&records);
+        // The kind byte of the second record, after its length, times and
+        // source ID.
+        let second = capture(&records[..1]).len();
+        damaged[second + 4 + 18 + records[1].source.len()] = 9;
+
+        std::fs::write(&path, &damaged).unwrap();
+        let result = CaptureWriter::append(&path);
+        let contents = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(result.err().map(|e| e.kind()), Some(io::ErrorKind::InvalidData));
+        assert_eq!(contents, damaged);
+    }
+
+    #[test]
+    fn it_should_reject_a_replay_speed_that_is_not_positive() {
+        let capture = capture(&records());
+
+        for scale in &[0.0, -1.0, f64::NAN, f64::INFINITY] {
+            let replay = CaptureReader::new(&capture[..]).unwrap().replay(Speed::Scaled(*scale));
+            assert_eq!(replay.err().map(|e| e.kind()), Some(io::ErrorKind::InvalidInput), "{}", scale);
+        }
+    }
+}
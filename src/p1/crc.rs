@@ -0,0 +1,127 @@
+// CRC-16/ARC as used by DSMR: polynomial 0x8005, reflected, initial value
+// and final XOR of zero.
+const POLYNOMIAL: u16 = 0xA001;
+
+const TABLE: [u16; 256] = make_table();
+
+// The high bytes of the table entries are all different, which makes each
+// step of the CRC reversible.
+#[cfg(feature = "std")]
+const INVERSE: [u8; 256] = make_inverse();
+
+const fn make_table() -> [u16; 256] {
+    let mut table = [0u16; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u16;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ POLYNOMIAL } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+#[cfg(feature = "std")]
+const fn make_inverse() -> [u8; 256] {
+    let mut inverse = [0u8; 256];
+    let mut i = 0;
+    while i < 256 {
+        inverse[(TABLE[i] >> 8) as usize] = i as u8;
+        i += 1;
+    }
+    inverse
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Crc16 {
+    crc: u16,
+}
+
+impl Crc16 {
+    pub fn new() -> Crc16 {
+        Crc16 { crc: 0 }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        let mut crc = self.crc;
+        for byte in data {
+            crc = (crc >> 8) ^ TABLE[((crc ^ u16::from(*byte)) & 0xFF) as usize];
+        }
+        self.crc = crc;
+    }
+
+    pub fn value(&self) -> u16 {
+        self.crc
+    }
+}
+
+pub fn checksum(data: &[u8]) -> u16 {
+    let mut crc = Crc16::new();
+    crc.update(data);
+    crc.value()
+}
+
+// Returns the CRC value that update(&[0]) turned into the given value.
+#[cfg(feature = "std")]
+pub(super) fn unshift_zero_byte(crc: u16) -> u16 {
+    let index = INVERSE[(crc >> 8) as usize];
+    (((crc ^ TABLE[index as usize]) & 0xFF) << 8) | u16::from(index)
+}
+
+// Returns the byte that, fed to a CRC of zero, yields the given value.
+#[cfg(feature = "std")]
+pub(super) fn byte_for(crc: u16) -> Option<u8> {
+    let index = INVERSE[(crc >> 8) as usize];
+    if TABLE[index as usize] == crc {
+        Some(index)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_should_compute_the_check_value_of_crc_16_arc() {
+        assert_eq!(checksum(b"123456789"), 0xBB3D);
+    }
+
+    #[test]
+    fn it_should_compute_the_same_value_when_updated_incrementally() {
+        let correct_datagram_1: &[u8] = include_bytes!("correct_datagram_1.test");
+        let data = &correct_datagram_1[..correct_datagram_1.len() - 4];
+
+        let mut crc = Crc16::new();
+        for chunk in data.chunks(7) {
+            crc.update(chunk);
+        }
+
+        assert_eq!(crc.value(), 0xE47C);
+        assert_eq!(checksum(data), 0xE47C);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn it_should_undo_the_update_for_a_zero_byte() {
+        for value in 0..=0xFFFFu16 {
+            let mut crc = Crc16 { crc: value };
+            crc.update(&[0]);
+            assert_eq!(unshift_zero_byte(crc.value()), value);
+        }
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn it_should_find_the_byte_for_a_crc_value() {
+        for byte in 0..=0xFFu8 {
+            assert_eq!(byte_for(checksum(&[byte])), Some(byte));
+        }
+        assert_eq!(byte_for(0x0001), None);
+    }
+}
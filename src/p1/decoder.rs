@@ -0,0 +1,447 @@
+use std::mem;
+use std::vec;
+use super::{check_datagram_crc, CrcPolicy, ReadDatagram};
+use super::crc::Crc16;
+use super::framing::{Framing, LineEnd, State, Tail, MAX_CRC_TEXT_LENGTH};
+use super::parity::{strip_even_parity, Detector, Parity};
+use super::reader::ReaderEvent;
+use super::timestamp::{Received, Timestamp};
+
+// DSMR telegrams are a few kilobytes at most, even with a long text message.
+pub const DEFAULT_MAX_DATAGRAM_SIZE: usize = 16 * 1024;
+
+// Splits a byte stream into datagrams without doing any I/O itself. It keeps
+// the partially framed datagram between calls to feed, so input can arrive in
+// chunks of any size. Neither the datagram nor the garbage before it grows
+// beyond max_datagram_size.
+#[derive(Debug, Clone)]
+pub struct P1Decoder {
+    state: State,
+    datagram: Vec<u8>,
+    garbage: Vec<u8>,
+    crc: Crc16,
+    crc_start: usize,
+    first_byte: Option<Timestamp>,
+    crc_byte: Option<Timestamp>,
+    verify_crc: bool,
+    crc_policy: CrcPolicy,
+    max_datagram_size: usize,
+    framing: Framing,
+    tail: Tail,
+    parity: Parity,
+    detector: Detector,
+    parity_errors: u64,
+    stripped: Vec<u8>,
+    window: Vec<u8>,
+    replay: Vec<u8>,
+    replay_offset: usize,
+    replay_parity: Parity,
+}
+
+impl P1Decoder {
+    pub fn new() -> P1Decoder {
+        P1Decoder {
+            state: State::Sync,
+            datagram: Vec::new(),
+            garbage: Vec::new(),
+            crc: Crc16::new(),
+            crc_start: 0,
+            first_byte: None,
+            crc_byte: None,
+            verify_crc: false,
+            crc_policy: CrcPolicy::default(),
+            max_datagram_size: DEFAULT_MAX_DATAGRAM_SIZE,
+            framing: Framing::default(),
+            tail: Tail::new(),
+            parity: Parity::default(),
+            detector: Detector::default(),
+            parity_errors: 0,
+            stripped: Vec::new(),
+            window: Vec::new(),
+            replay: Vec::new(),
+            replay_offset: 0,
+            replay_parity: Parity::None,
+        }
+    }
+
+    // Verifies the CRC of each complete datagram, so the decoder yields the
+    // same results as passing them through verify_crc.
+    pub fn verify_crc(mut self, verify_crc: bool) -> P1Decoder {
+        self.verify_crc = verify_crc;
+        self
+    }
+
+    pub fn crc_policy(mut self, crc_policy: CrcPolicy) -> P1Decoder {
+        self.crc_policy = crc_policy;
+        self
+    }
+
+    // Limits the memory used for a single datagram or a run of garbage. Larger
+    // datagrams are reported as Oversized and garbage is reported in chunks.
+    pub fn max_datagram_size(mut self, max_datagram_size: usize) -> P1Decoder {
+        assert!(max_datagram_size > 0, "max_datagram_size must be positive");
+        self.max_datagram_size = max_datagram_size;
+        self
+    }
+
+    pub fn framing(mut self, framing: Framing) -> P1Decoder {
+        self.framing = framing;
+        self
+    }
+
+    pub fn parity(mut self, parity: Parity) -> P1Decoder {
+        self.parity = parity;
+        self
+    }
+
+    // Returns the parity in use, which is Auto until it has been detected.
+    pub fn detected_parity(&self) -> Parity {
+        self.parity
+    }
+
+    // Returns the number of parity errors since the last call.
+    pub fn take_parity_errors(&mut self) -> u64 {
+        mem::replace(&mut self.parity_errors, 0)
+    }
+
+    // Returns the Datagram, Garbage and Oversized events completed by the
+    // given bytes. All of the bytes are decoded before this returns.
+    pub fn feed(&mut self, bytes: &[u8]) -> Feed {
+        let mut events = Vec::new();
+        let mut bytes = bytes;
+        loop {
+            let (consumed, event) = self.process(bytes);
+            bytes = &bytes[consumed..];
+            match event {
+                Some(event) => events.push(event),
+                None if bytes.is_empty() => break,
+                None => {},
+            }
+        }
+        Feed { events: events.into_iter() }
+    }
+
+    // Returns the number of bytes consumed, which is less than the input
+    // when an event was produced.
+    pub(super) fn process(&mut self, bytes: &[u8]) -> (usize, Option<ReaderEvent>) {
+        if let Some(event) = self.replay_next() {
+            return (0, Some(event));
+        }
+        match self.parity {
+            // Holds back the bytes of the detection window, and frames them
+            // once the window is complete, so the first datagram is framed
+            // with the parity that was detected.
+            Parity::Auto => {
+                let window = bytes.len().min(self.detector.remaining());
+                let complete = window == self.detector.remaining();
+                self.window.extend_from_slice(&bytes[..window]);
+                let detected = self.detector.observe(&bytes[..window]);
+                if !complete {
+                    return (window, None);
+                }
+                if let Some(parity) = detected {
+                    self.parity = parity;
+                }
+                self.start_replay(detected.unwrap_or(Parity::None));
+                (window, self.replay_next())
+            },
+            parity => self.decode(parity, bytes),
+        }
+    }
+
+    fn decode(&mut self, parity: Parity, bytes: &[u8]) -> (usize, Option<ReaderEvent>) {
+        match parity {
+            Parity::Even => {
+                let mut stripped = mem::take(&mut self.stripped);
+                stripped.clear();
+                stripped.extend(bytes.iter().map(|b| strip_even_parity(*b).0));
+                let (consumed, event) = self.frame(&stripped);
+                self.stripped = stripped;
+                self.parity_errors += bytes[..consumed].iter().filter(|b| !strip_even_parity(**b).1).count() as u64;
+                (consumed, event)
+            },
+            _ => self.frame(bytes),
+        }
+    }
+
+    fn start_replay(&mut self, parity: Parity) {
+        self.replay = mem::take(&mut self.window);
+        self.replay_offset = 0;
+        self.replay_parity = parity;
+    }
+
+    // Frames the held back detection window up to the next event.
+    fn replay_next(&mut self) -> Option<ReaderEvent> {
+        if self.replay_offset == self.replay.len() {
+            return None;
+        }
+        let replay = mem::take(&mut self.replay);
+        let (consumed, event) = self.decode(self.replay_parity, &replay[self.replay_offset..]);
+        self.replay_offset += consumed;
+        self.replay = replay;
+        if self.replay_offset == self.replay.len() {
+            self.replay.clear();
+            self.replay_offset = 0;
+        }
+        event
+    }
+
+    fn frame(&mut self, bytes: &[u8]) -> (usize, Option<ReaderEvent>) {
+        let mut offset = 0;
+        while offset < bytes.len() {
+            let (consumed, event) = self.step(&bytes[offset..]);
+            self.tail.track(&bytes[offset..offset + consumed]);
+            offset += consumed;
+            if event.is_some() {
+                return (offset, event);
+            }
+        }
+        (offset, None)
+    }
+
+    // Produces the next of the last events at the end of the input, so it
+    // should be called until it returns None. The decoder can be fed again
+    // afterwards.
+    pub fn finish(&mut self) -> Option<ReaderEvent> {
+        // A detection window cut short by the end of the input is framed as
+        // it arrived.
+        if !self.window.is_empty() {
+            self.detector = Detector::default();
+            self.start_replay(Parity::None);
+        }
+        if let Some(event) = self.replay_next() {
+            return Some(event);
+        }
+        match self.state {
+            State::Sync if self.garbage.is_empty() => None,
+            State::Sync => Some(ReaderEvent::Garbage(mem::take(&mut self.garbage).into_boxed_slice())),
+            State::Data => Some(self.incomplete_datagram()),
+            State::CrcText => Some(self.finish_datagram(LineEnd::Eof)),
+            State::CarriageReturn => Some(self.finish_datagram(LineEnd::Other)),
+        }
+    }
+
+    fn step(&mut self, bytes: &[u8]) -> (usize, Option<ReaderEvent>) {
+        match self.state {
+            State::Sync => {
+                let room = self.max_datagram_size - self.garbage.len();
+                let dropped_bytes = (0..bytes.len().min(room)).take_while(|i| !self.tail.starts_datagram(self.framing, bytes, *i)).count();
+                self.garbage.extend_from_slice(&bytes[..dropped_bytes]);
+                if self.garbage.len() == self.max_datagram_size {
+                    (dropped_bytes, Some(ReaderEvent::Garbage(mem::take(&mut self.garbage).into_boxed_slice())))
+                } else if dropped_bytes == bytes.len() {
+                    (dropped_bytes, None)
+                } else if !self.garbage.is_empty() {
+                    (dropped_bytes, Some(ReaderEvent::Garbage(mem::take(&mut self.garbage).into_boxed_slice())))
+                } else {
+                    self.datagram.push(b'/');
+                    self.first_byte = Some(Timestamp::now());
+                    self.crc_byte = None;
+                    self.crc = Crc16::new();
+                    self.crc.update(b"/");
+                    self.state = State::Data;
+                    (dropped_bytes + 1, None)
+                }
+            },
+            State::Data => {
+                let datagram_bytes = (0..bytes.len()).take_while(|i| bytes[*i] != b'!' && !self.tail.starts_datagram(self.framing, bytes, *i)).count();
+                if self.datagram.len() + datagram_bytes >= self.max_datagram_size {
+                    return self.oversized_datagram(bytes);
+                }
+                self.datagram.extend_from_slice(&bytes[..datagram_bytes]);
+                self.crc.update(&bytes[..datagram_bytes]);
+                if datagram_bytes == bytes.len() {
+                    (datagram_bytes, None)
+                } else if bytes[datagram_bytes] != b'!' {
+                    (datagram_bytes, Some(self.incomplete_datagram()))
+                } else {
+                    self.datagram.push(b'!');
+                    self.crc_byte = Some(Timestamp::now());
+                    self.crc.update(b"!");
+                    self.crc_start = self.datagram.len();
+                    self.state = State::CrcText;
+                    (datagram_bytes + 1, None)
+                }
+            },
+            State::CrcText => {
+                let text_length = self.datagram.len() - self.crc_start;
+                let crc_bytes = bytes.iter().take(MAX_CRC_TEXT_LENGTH - text_length).take_while(|b| self.framing.is_crc_text(**b)).count();
+                self.datagram.extend_from_slice(&bytes[..crc_bytes]);
+                if crc_bytes == bytes.len() {
+                    return (crc_bytes, None);
+                }
+                match bytes[crc_bytes] {
+                    b'/' if self.framing == Framing::Any => (crc_bytes, Some(self.finish_datagram(LineEnd::NextDatagram))),
+                    b'\r' => {
+                        self.state = State::CarriageReturn;
+                        (crc_bytes + 1, None)
+                    },
+                    b'\n' => (crc_bytes + 1, Some(self.finish_datagram(LineEnd::Other))),
+                    _ => (crc_bytes, Some(self.finish_datagram(LineEnd::Other))),
+                }
+            },
+            State::CarriageReturn => match bytes[0] {
+                b'\n' => (1, Some(self.finish_datagram(LineEnd::CrLf))),
+                _ => (0, Some(self.finish_datagram(LineEnd::Other))),
+            },
+        }
+    }
+
+    // Gives up on a datagram that would not leave room for its CRC line. The
+    // rest of it is skipped as garbage up to the start of the next datagram.
+    fn oversized_datagram(&mut self, bytes: &[u8]) -> (usize, Option<ReaderEvent>) {
+        let consumed = self.max_datagram_size - self.datagram.len();
+        self.datagram.extend_from_slice(&bytes[..consumed]);
+        self.state = State::Sync;
+        (consumed, Some(ReaderEvent::Oversized(mem::take(&mut self.datagram).into_boxed_slice())))
+    }
+
+    fn incomplete_datagram(&mut self) -> ReaderEvent {
+        self.state = State::Sync;
+        ReaderEvent::Datagram(ReadDatagram::IncompleteDatagram(mem::take(&mut self.datagram).into_boxed_slice()), self.received())
+    }
+
+    fn received(&mut self) -> Received {
+        let first_byte = self.first_byte.take().unwrap_or_else(Timestamp::now);
+        Received { first_byte, crc_byte: self.crc_byte.take() }
+    }
+
+    fn finish_datagram(&mut self, line_end: LineEnd) -> ReaderEvent {
+        let text_length = self.datagram.len() - self.crc_start;
+        let truncated = line_end == LineEnd::Eof || line_end == LineEnd::NextDatagram;
+        if truncated && text_length < 4 {
+            return self.incomplete_datagram();
+        }
+        self.state = State::Sync;
+        let datagram = mem::take(&mut self.datagram).into_boxed_slice();
+        if self.verify_crc {
+            let datagram = check_datagram_crc(datagram, self.crc.value(), &self.crc_policy, line_end == LineEnd::CrLf);
+            ReaderEvent::Datagram(datagram, self.received())
+        } else {
+            ReaderEvent::Datagram(ReadDatagram::Datagram(datagram), self.received())
+        }
+    }
+}
+
+impl Default for P1Decoder {
+    fn default() -> P1Decoder {
+        P1Decoder::new()
+    }
+}
+
+pub struct Feed {
+    events: vec::IntoIter<ReaderEvent>,
+}
+
+impl Iterator for Feed {
+    type Item = ReaderEvent;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.events.next()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode_in_chunks(decoder: &mut P1Decoder, input: &[u8], chunk_size: usize) -> Vec<ReadDatagram> {
+        let mut datagrams = Vec::new();
+        for chunk in input.chunks(chunk_size) {
+            for event in decoder.feed(chunk) {
+                if let ReaderEvent::Datagram(datagram, _) = event {
+                    datagrams.push(datagram);
+                }
+            }
+        }
+        if let Some(ReaderEvent::Datagram(datagram, _)) = decoder.finish() {
+            datagrams.push(datagram);
+        }
+        datagrams
+    }
+
+    #[test]
+    fn it_should_decode_the_same_datagrams_for_any_chunk_size() {
+        let correct_datagram_1: &[u8] = include_bytes!("correct_datagram_1.test");
+        let correct_datagram_2: &[u8] = include_bytes!("correct_datagram_2.test");
+        let mut combined_input: Vec<u8> = Vec::new();
+        combined_input.extend_from_slice(&[4, 23, 32]);
+        combined_input.extend_from_slice(correct_datagram_1);
+        combined_input.extend_from_slice(b"\r\n");
+        combined_input.extend_from_slice(&correct_datagram_1[..200]);
+        combined_input.extend_from_slice(correct_datagram_2);
+
+        let expected = vec!(
+            ReadDatagram::Datagram(correct_datagram_1.to_vec().into_boxed_slice()),
+            ReadDatagram::IncompleteDatagram(correct_datagram_1[..200].to_vec().into_boxed_slice()),
+            ReadDatagram::Datagram(correct_datagram_2.to_vec().into_boxed_slice()),
+        );
+        for chunk_size in 1..=combined_input.len() {
+            assert_eq!(decode_in_chunks(&mut P1Decoder::new(), &combined_input, chunk_size), expected, "chunk size {}", chunk_size);
+        }
+    }
+
+    #[test]
+    fn it_should_yield_every_event_completed_by_a_single_feed() {
+        let correct_datagram_1: &[u8] = include_bytes!("correct_datagram_1.test");
+        let mut combined_input: Vec<u8> = Vec::new();
+        combined_input.extend_from_slice(&[4, 23, 32]);
+        combined_input.extend_from_slice(correct_datagram_1);
+        combined_input.extend_from_slice(b"\r\n");
+        combined_input.extend_from_slice(correct_datagram_1);
+        combined_input.extend_from_slice(b"\r\n");
+        let mut decoder = P1Decoder::new().verify_crc(true);
+
+        let events: Vec<ReaderEvent> = decoder.feed(&combined_input).collect();
+
+        assert_eq!(events.len(), 3);
+        match events[0] {
+            ReaderEvent::Garbage(ref garbage) => assert_eq!(**garbage, [4, 23, 32]),
+            ref event => panic!("unexpected event {:?}", event),
+        }
+        for event in &events[1..] {
+            match *event {
+                ReaderEvent::Datagram(ref datagram, _) => assert_eq!(*datagram, ReadDatagram::Datagram(correct_datagram_1.to_vec().into_boxed_slice())),
+                ref event => panic!("unexpected event {:?}", event),
+            }
+        }
+        assert!(decoder.finish().is_none());
+    }
+
+    #[test]
+    fn it_should_keep_the_input_of_a_feed_that_is_dropped_early() {
+        let correct_datagram_1: &[u8] = include_bytes!("correct_datagram_1.test");
+        let mut combined_input: Vec<u8> = Vec::new();
+        combined_input.extend_from_slice(correct_datagram_1);
+        combined_input.extend_from_slice(b"\r\n");
+        combined_input.extend_from_slice(&correct_datagram_1[..200]);
+        let mut decoder = P1Decoder::new();
+
+        assert!(decoder.feed(&combined_input).next().is_some());
+        decoder.feed(&correct_datagram_1[200..]);
+
+        assert_eq!(decoder.finish().map(|event| match event {
+            ReaderEvent::Datagram(datagram, _) => datagram,
+            event => panic!("unexpected event {:?}", event),
+        }), Some(ReadDatagram::Datagram(correct_datagram_1.to_vec().into_boxed_slice())));
+    }
+
+    #[test]
+    fn it_should_frame_the_bytes_used_to_detect_the_parity() {
+        let correct_datagram_1: &[u8] = include_bytes!("correct_datagram_1.test");
+        let mut input: Vec<u8> = correct_datagram_1.iter().map(|b| if b.count_ones().is_multiple_of(2) { *b } else { *b | 0x80 }).collect();
+        input.extend_from_slice(&[0x8D, 0x0A]);
+        let expected = vec![ReadDatagram::Datagram(correct_datagram_1.to_vec().into_boxed_slice())];
+
+        for chunk_size in &[1, 7, 128, 1000] {
+            let mut decoder = P1Decoder::new().parity(Parity::Auto);
+            assert_eq!(decode_in_chunks(&mut decoder, &input, *chunk_size), expected, "chunk size {}", chunk_size);
+            assert_eq!(decoder.detected_parity(), Parity::Even);
+        }
+
+        let mut decoder = P1Decoder::new().parity(Parity::Auto);
+        assert_eq!(decode_in_chunks(&mut decoder, &correct_datagram_1[..100], 10), vec![
+            ReadDatagram::IncompleteDatagram(correct_datagram_1[..100].to_vec().into_boxed_slice()),
+        ]);
+    }
+}
@@ -0,0 +1,260 @@
+use obis::ObisIdentifier;
+use super::telegram::{CosemObject, Telegram, Value};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Change {
+    IdentificationChanged { old: String, new: String },
+    Added(CosemObject),
+    Removed(CosemObject),
+    ValueChanged {
+        id: ObisIdentifier,
+        index: usize,
+        old: Option<String>,
+        new: Option<String>,
+        delta: Option<f64>,
+    },
+    UnitChanged {
+        id: ObisIdentifier,
+        index: usize,
+        old: Option<String>,
+        new: Option<String>,
+    },
+    TimestampChanged {
+        id: ObisIdentifier,
+        index: usize,
+        old: String,
+        new: String,
+        seconds: i64,
+    },
+}
+
+impl Telegram {
+    pub fn diff(&self, other: &Telegram) -> Vec<Change> {
+        let mut changes = Vec::new();
+        if self.identification != other.identification {
+            changes.push(Change::IdentificationChanged {
+                old: self.identification.clone(),
+                new: other.identification.clone(),
+            });
+        }
+
+        let mut matched = vec![false; other.objects.len()];
+        for object in &self.objects {
+            let position = other.objects.iter().enumerate()
+                .position(|(i, o)| !matched[i] && o.id == object.id);
+            match position {
+                Some(i) => {
+                    matched[i] = true;
+                    diff_values(object, &other.objects[i], &mut changes);
+                },
+                None => changes.push(Change::Removed(object.clone())),
+            }
+        }
+        for (object, matched) in other.objects.iter().zip(matched) {
+            if !matched {
+                changes.push(Change::Added(object.clone()));
+            }
+        }
+        changes
+    }
+}
+
+fn diff_values(old: &CosemObject, new: &CosemObject, changes: &mut Vec<Change>) {
+    let count = old.values.len().max(new.values.len());
+    for index in 0..count {
+        let old_value = old.values.get(index);
+        let new_value = new.values.get(index);
+        if old_value == new_value {
+            continue;
+        }
+
+        let old_unit = old_value.and_then(|v| v.unit.clone());
+        let new_unit = new_value.and_then(|v| v.unit.clone());
+        let same_unit = old_unit == new_unit;
+        if old_value.is_some() && new_value.is_some() && !same_unit {
+            changes.push(Change::UnitChanged { id: old.id.clone(), index, old: old_unit, new: new_unit });
+        }
+
+        let old_text = old_value.map(|v| v.text.clone());
+        let new_text = new_value.map(|v| v.text.clone());
+        if old_text == new_text {
+            continue;
+        }
+        if let (Some(o), Some(n)) = (old_value, new_value) {
+            if let (Some(o_time), Some(n_time)) = (timestamp(o), timestamp(n)) {
+                changes.push(Change::TimestampChanged {
+                    id: old.id.clone(),
+                    index,
+                    old: o.text.clone(),
+                    new: n.text.clone(),
+                    seconds: n_time - o_time,
+                });
+                continue;
+            }
+        }
+        let delta = match (old_value, new_value) {
+            (Some(o), Some(n)) if same_unit => match (o.text.parse::<f64>(), n.text.parse::<f64>()) {
+                (Ok(o), Ok(n)) => Some(n - o),
+                _ => None,
+            },
+            _ => None,
+        };
+        changes.push(Change::ValueChanged { id: old.id.clone(), index, old: old_text, new: new_text, delta });
+    }
+}
+
+// Parses a YYMMDDhhmmssX timestamp into seconds since 2000-01-01 UTC, where X
+// is S for summer time (UTC+2) or W for winter time (UTC+1).
+fn timestamp(value: &Value) -> Option<i64> {
+    let text = value.text.as_bytes();
+    if value.unit.is_some() || text.len() != 13 || !text[..12].iter().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let offset = match text[12] {
+        b'S' => 7200,
+        b'W' => 3600,
+        _ => return None,
+    };
+    let field = |i: usize| i64::from(text[i] - b'0') * 10 + i64::from(text[i + 1] - b'0');
+    let (year, month, day) = (2000 + field(0), field(2), field(4));
+    let (hour, minute, second) = (field(6), field(8), field(10));
+    if !(1..=12).contains(&month) || !(1..=days_in_month(year, month)).contains(&day) || hour > 23 || minute > 59 || second > 59 {
+        return None;
+    }
+    let days = days_since_2000(year, month, day);
+    Some(days * 86400 + hour * 3600 + minute * 60 + second - offset)
+}
+
+fn is_leap_year(year: i64) -> bool {
+    (year % 4 == 0 && year % 100 != 0) || year % 400 == 0
+}
+
+fn days_in_month(year: i64, month: i64) -> i64 {
+    match month {
+        2 if is_leap_year(year) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+fn days_since_2000(year: i64, month: i64, day: i64) -> i64 {
+    let days = (2000..year).map(|y| if is_leap_year(y) { 366 } else { 365 }).sum::<i64>();
+    days + (1..month).map(|m| days_in_month(year, m)).sum::<i64>() + day - 1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id(id: &str) -> ObisIdentifier {
+        ObisIdentifier::parse(id).unwrap()
+    }
+
+    fn edited_datagram(edits: &[(&str, &str)]) -> Telegram {
+        let correct_datagram_1: &[u8] = include_bytes!("correct_datagram_1.test");
+        let mut text = String::from_utf8(correct_datagram_1.to_vec()).unwrap();
+        for &(from, to) in edits {
+            text = text.replace(from, to);
+        }
+        Telegram::parse(text.as_bytes()).0
+    }
+
+    #[test]
+    fn it_should_report_no_changes_between_equal_telegrams() {
+        let telegram = edited_datagram(&[]);
+
+        assert_eq!(telegram.diff(&telegram), vec![]);
+    }
+
+    #[test]
+    fn it_should_report_changed_values_with_deltas() {
+        let old = edited_datagram(&[]);
+        let new = edited_datagram(&[("1-0:1.8.1(123456.789*kWh)", "1-0:1.8.1(123457.000*kWh)")]);
+
+        let changes = old.diff(&new);
+
+        assert_eq!(changes.len(), 1);
+        match changes[0] {
+            Change::ValueChanged { ref id, index: 0, delta: Some(delta), .. } => {
+                assert_eq!(*id, ObisIdentifier::parse("1-0:1.8.1").unwrap());
+                assert!((delta - 0.211).abs() < 1e-6);
+            },
+            ref change => panic!("unexpected change {:?}", change),
+        }
+    }
+
+    #[test]
+    fn it_should_report_unit_changes_without_delta() {
+        let old = edited_datagram(&[]);
+        let new = edited_datagram(&[("1-0:1.7.0(01.193*kW)", "1-0:1.7.0(1193*W)")]);
+
+        assert_eq!(old.diff(&new), vec![
+            Change::UnitChanged { id: id("1-0:1.7.0"), index: 0, old: Some("kW".to_owned()), new: Some("W".to_owned()) },
+            Change::ValueChanged {
+                id: id("1-0:1.7.0"),
+                index: 0,
+                old: Some("01.193".to_owned()),
+                new: Some("1193".to_owned()),
+                delta: None,
+            },
+        ]);
+    }
+
+    #[test]
+    fn it_should_report_timestamp_changes_across_daylight_saving_time() {
+        let old = edited_datagram(&[("0-0:1.0.0(101209113020W)", "0-0:1.0.0(170326015959W)")]);
+        let new = edited_datagram(&[("0-0:1.0.0(101209113020W)", "0-0:1.0.0(170326030000S)")]);
+
+        assert_eq!(old.diff(&new), vec![Change::TimestampChanged {
+            id: id("0-0:1.0.0"),
+            index: 0,
+            old: "170326015959W".to_owned(),
+            new: "170326030000S".to_owned(),
+            seconds: 1,
+        }]);
+    }
+
+    #[test]
+    fn it_should_not_treat_an_out_of_range_date_as_a_timestamp() {
+        for text in &["171326015959W", "170230015959W", "170326245959W", "170326016059S", "170326015960S", "170300015959W"] {
+            let value = Value { text: text.to_string(), unit: None };
+            assert_eq!(timestamp(&value), None, "{}", text);
+        }
+        assert!(timestamp(&Value { text: "160229235959W".to_owned(), unit: None }).is_some());
+
+        let old = edited_datagram(&[]);
+        let new = edited_datagram(&[("0-0:1.0.0(101209113020W)", "0-0:1.0.0(101309113020W)")]);
+        assert_eq!(old.diff(&new), vec![Change::ValueChanged {
+            id: id("0-0:1.0.0"),
+            index: 0,
+            old: Some("101209113020W".to_owned()),
+            new: Some("101309113020W".to_owned()),
+            delta: None,
+        }]);
+    }
+
+    #[test]
+    fn it_should_report_added_and_removed_objects() {
+        let correct_datagram_2: &[u8] = include_bytes!("correct_datagram_2.test");
+        let old = edited_datagram(&[]);
+        let (new, _) = Telegram::parse(correct_datagram_2);
+
+        let changes = old.diff(&new);
+
+        let removed: Vec<String> = changes.iter().filter_map(|c| match *c {
+            Change::Removed(ref o) => Some(o.id.to_string()),
+            _ => None,
+        }).collect();
+        let added: Vec<String> = changes.iter().filter_map(|c| match *c {
+            Change::Added(ref o) => Some(o.id.to_string()),
+            _ => None,
+        }).collect();
+        assert_eq!(removed, vec!["1-0:32.7.0.255", "1-0:52.7.0.255", "1-0:72.7.0.255"]);
+        assert_eq!(added, vec!["0-0:96.13.1.255"]);
+        assert_eq!(changes[0], Change::IdentificationChanged {
+            old: "ISk5\\2MT382-1000".to_owned(),
+            new: "XMX5LGBBFG1009196767".to_owned(),
+        });
+    }
+}
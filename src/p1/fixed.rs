@@ -0,0 +1,251 @@
+use super::{classify_crc_text, CrcError, CrcPolicy};
+use super::crc::Crc16;
+use super::framing::{Framing, LineEnd, State, Tail, MAX_CRC_TEXT_LENGTH};
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Frame<'a> {
+    Datagram(&'a [u8]),
+    IncompleteDatagram(&'a [u8]),
+    InvalidCrc {
+        datagram: &'a [u8],
+        actual_crc: u16,
+        error: CrcError,
+    },
+    Oversized,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Emit {
+    Incomplete,
+    Datagram(LineEnd),
+    Oversized,
+}
+
+// Frames datagrams into a buffer provided by the caller, for targets without
+// an allocator. It follows the same rules as P1Decoder, but it always checks
+// the CRC and skips garbage without reporting it. A datagram that does not
+// fit the buffer is reported as Oversized.
+pub struct FixedDecoder<'b> {
+    buffer: &'b mut [u8],
+    length: usize,
+    state: State,
+    crc: Crc16,
+    crc_start: usize,
+    crc_policy: CrcPolicy,
+    framing: Framing,
+    tail: Tail,
+}
+
+impl<'b> FixedDecoder<'b> {
+    pub fn new(buffer: &'b mut [u8]) -> FixedDecoder<'b> {
+        FixedDecoder {
+            buffer,
+            length: 0,
+            state: State::Sync,
+            crc: Crc16::new(),
+            crc_start: 0,
+            crc_policy: CrcPolicy::default(),
+            framing: Framing::default(),
+            tail: Tail::new(),
+        }
+    }
+
+    pub fn crc_policy(mut self, crc_policy: CrcPolicy) -> FixedDecoder<'b> {
+        self.crc_policy = crc_policy;
+        self
+    }
+
+    pub fn framing(mut self, framing: Framing) -> FixedDecoder<'b> {
+        self.framing = framing;
+        self
+    }
+
+    // Returns the number of bytes consumed, which is less than the input
+    // when a frame was produced. The frame borrows the buffer, so it has to
+    // be handled before the decoder is fed again.
+    pub fn feed(&mut self, bytes: &[u8]) -> (usize, Option<Frame<'_>>) {
+        for (offset, byte) in bytes.iter().enumerate() {
+            if let Some((consumed, emit)) = self.step(*byte) {
+                if consumed {
+                    self.tail.track(&[*byte]);
+                }
+                return (offset + consumed as usize, Some(self.emit(emit)));
+            }
+            self.tail.track(&[*byte]);
+        }
+        (bytes.len(), None)
+    }
+
+    // Produces the last frame at the end of the input, if any.
+    pub fn finish(&mut self) -> Option<Frame<'_>> {
+        match self.state {
+            State::Sync => None,
+            State::Data => Some(self.emit(Emit::Incomplete)),
+            State::CrcText => Some(self.emit(Emit::Datagram(LineEnd::Eof))),
+            State::CarriageReturn => Some(self.emit(Emit::Datagram(LineEnd::Other))),
+        }
+    }
+
+    // Returns whether the byte was consumed along with the frame it ends.
+    fn step(&mut self, byte: u8) -> Option<(bool, Emit)> {
+        let starts_datagram = self.tail.starts_datagram(self.framing, &[byte], 0);
+        match self.state {
+            State::Sync if starts_datagram => {
+                self.length = 0;
+                self.crc = Crc16::new();
+                self.state = State::Data;
+                self.push_data(byte)
+            },
+            State::Sync => None,
+            State::Data if starts_datagram => Some((false, Emit::Incomplete)),
+            State::Data => {
+                let emit = self.push_data(byte);
+                if byte == b'!' {
+                    self.crc_start = self.length;
+                    self.state = State::CrcText;
+                }
+                emit
+            },
+            State::CrcText => {
+                let text_length = self.length - self.crc_start;
+                if text_length < MAX_CRC_TEXT_LENGTH && self.framing.is_crc_text(byte) {
+                    return self.push(byte);
+                }
+                match byte {
+                    b'/' if self.framing == Framing::Any => Some((false, Emit::Datagram(LineEnd::NextDatagram))),
+                    b'\r' => {
+                        self.state = State::CarriageReturn;
+                        None
+                    },
+                    b'\n' => Some((true, Emit::Datagram(LineEnd::Other))),
+                    _ => Some((false, Emit::Datagram(LineEnd::Other))),
+                }
+            },
+            State::CarriageReturn => match byte {
+                b'\n' => Some((true, Emit::Datagram(LineEnd::CrLf))),
+                _ => Some((false, Emit::Datagram(LineEnd::Other))),
+            },
+        }
+    }
+
+    fn push(&mut self, byte: u8) -> Option<(bool, Emit)> {
+        if self.length == self.buffer.len() {
+            return Some((true, Emit::Oversized));
+        }
+        self.buffer[self.length] = byte;
+        self.length += 1;
+        None
+    }
+
+    fn push_data(&mut self, byte: u8) -> Option<(bool, Emit)> {
+        self.crc.update(&[byte]);
+        self.push(byte)
+    }
+
+    fn emit(&mut self, emit: Emit) -> Frame<'_> {
+        self.state = State::Sync;
+        let datagram = &self.buffer[..self.length];
+        match emit {
+            Emit::Oversized => Frame::Oversized,
+            Emit::Incomplete => Frame::IncompleteDatagram(datagram),
+            Emit::Datagram(LineEnd::Eof) | Emit::Datagram(LineEnd::NextDatagram) if self.length - self.crc_start < 4 => {
+                Frame::IncompleteDatagram(datagram)
+            },
+            Emit::Datagram(line_end) => {
+                let actual_crc = self.crc.value();
+                match classify_crc_text(&datagram[self.crc_start..], actual_crc, &self.crc_policy, line_end == LineEnd::CrLf) {
+                    Ok(()) => Frame::Datagram(datagram),
+                    Err(error) => Frame::InvalidCrc { datagram, actual_crc, error },
+                }
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frames_in_chunks(decoder: &mut FixedDecoder, input: &[u8], chunk_size: usize, check: &mut dyn FnMut(Frame)) {
+        for chunk in input.chunks(chunk_size) {
+            let mut chunk = chunk;
+            while !chunk.is_empty() {
+                let (consumed, frame) = decoder.feed(chunk);
+                if let Some(frame) = frame {
+                    check(frame);
+                }
+                chunk = &chunk[consumed..];
+            }
+        }
+        if let Some(frame) = decoder.finish() {
+            check(frame);
+        }
+    }
+
+    #[test]
+    fn it_should_frame_datagrams_into_a_fixed_buffer_for_any_chunk_size() {
+        let correct_datagram_1: &[u8] = include_bytes!("correct_datagram_1.test");
+        let correct_datagram_2: &[u8] = include_bytes!("correct_datagram_2.test");
+        let mut combined_input = [0u8; 4096];
+        let mut length = 0;
+        for part in &[&[4u8, 23, 32][..], correct_datagram_1, b"\r\n", &correct_datagram_1[..200], correct_datagram_2, b"\r\n"] {
+            combined_input[length..length + part.len()].copy_from_slice(part);
+            length += part.len();
+        }
+        let expected = [
+            Frame::Datagram(correct_datagram_1),
+            Frame::IncompleteDatagram(&correct_datagram_1[..200]),
+            Frame::Datagram(correct_datagram_2),
+        ];
+
+        for chunk_size in 1..=length {
+            let mut buffer = [0u8; 2048];
+            let mut decoder = FixedDecoder::new(&mut buffer);
+            let mut count = 0;
+            frames_in_chunks(&mut decoder, &combined_input[..length], chunk_size, &mut |frame| {
+                assert_eq!(frame, expected[count], "chunk size {}", chunk_size);
+                count += 1;
+            });
+            assert_eq!(count, expected.len());
+        }
+    }
+
+    #[test]
+    fn it_should_signal_an_invalid_crc() {
+        let correct_datagram_1: &[u8] = include_bytes!("correct_datagram_1.test");
+        let mut input = [0u8; 2048];
+        input[..correct_datagram_1.len()].copy_from_slice(correct_datagram_1);
+        input[100] = 15;
+        let mut buffer = [0u8; 2048];
+        let mut decoder = FixedDecoder::new(&mut buffer).crc_policy(CrcPolicy { allow_missing_line_end: true, ..CrcPolicy::default() });
+
+        let (consumed, frame) = decoder.feed(&input[..correct_datagram_1.len()]);
+
+        assert_eq!(consumed, correct_datagram_1.len());
+        assert_eq!(frame, None);
+        assert_eq!(decoder.finish(), Some(Frame::InvalidCrc {
+            datagram: &input[..correct_datagram_1.len()],
+            actual_crc: 0xBAD7,
+            error: CrcError::Mismatch { expected: 0xE47C },
+        }));
+    }
+
+    #[test]
+    fn it_should_report_a_datagram_that_does_not_fit_and_resynchronise() {
+        let correct_datagram_1: &[u8] = include_bytes!("correct_datagram_1.test");
+        let correct_datagram_2: &[u8] = include_bytes!("correct_datagram_2.test");
+        let mut buffer = [0u8; 1000];
+        let mut decoder = FixedDecoder::new(&mut buffer);
+        let mut frames = 0;
+
+        frames_in_chunks(&mut decoder, correct_datagram_1, 64, &mut |frame| {
+            assert_eq!(frame, Frame::Oversized);
+            frames += 1;
+        });
+        frames_in_chunks(&mut decoder, correct_datagram_2, 64, &mut |frame| {
+            assert_eq!(frame, Frame::InvalidCrc { datagram: correct_datagram_2, actual_crc: 0x6C8D, error: CrcError::MissingLineEnd });
+            frames += 1;
+        });
+        assert_eq!(frames, 2);
+    }
+}
@@ -0,0 +1,71 @@
+// Bounds the CRC text, so a CRC line that carries extra characters cannot
+// swallow the start of whatever follows it.
+pub(super) const MAX_CRC_TEXT_LENGTH: usize = 8;
+
+// By default any '/' starts a new datagram. With LineStart only a '/' at the
+// start of the input or right after a CRLF does, and the CRC line always runs
+// up to its line ending. That keeps a '/' inside a value or noise after the
+// '!' from truncating the datagram.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Framing {
+    #[default]
+    Any,
+    LineStart,
+}
+
+impl Framing {
+    pub(super) fn is_crc_text(self, b: u8) -> bool {
+        match self {
+            Framing::Any => b.is_ascii_hexdigit() || b == b' ' || b == b'\t',
+            Framing::LineStart => b != b'\r' && b != b'\n',
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum State {
+    Sync,
+    Data,
+    CrcText,
+    CarriageReturn,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum LineEnd {
+    CrLf,
+    Other,
+    NextDatagram,
+    Eof,
+}
+
+// The last two bytes consumed, to tell whether a '/' starts a line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) struct Tail([u8; 2]);
+
+impl Tail {
+    pub(super) fn new() -> Tail {
+        Tail(*b"\r\n")
+    }
+
+    pub(super) fn track(&mut self, consumed: &[u8]) {
+        match *consumed {
+            [] => {},
+            [b] => self.0 = [self.0[1], b],
+            [.., a, b] => self.0 = [a, b],
+        }
+    }
+
+    pub(super) fn starts_datagram(&self, framing: Framing, bytes: &[u8], offset: usize) -> bool {
+        if bytes[offset] != b'/' {
+            return false;
+        }
+        match framing {
+            Framing::Any => true,
+            Framing::LineStart => match offset {
+                0 => self.0 == *b"\r\n",
+                1 => self.0[1] == b'\r' && bytes[0] == b'\n',
+                _ => bytes[offset - 2..offset] == *b"\r\n",
+            },
+        }
+    }
+}
@@ -0,0 +1,333 @@
+use std::cmp;
+use std::error;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::thread;
+use std::time::{Duration, Instant};
+use obis::ObisIdentifier;
+use super::decoder::DEFAULT_MAX_DATAGRAM_SIZE;
+use super::reader::{Backoff, DatagramReader};
+use super::telegram::TelegramRef;
+
+// Leaves room for the status line and headers next to the telegram.
+const MAX_RESPONSE_SIZE: usize = DEFAULT_MAX_DATAGRAM_SIZE + 4096;
+
+// Polls a local HTTP endpoint that serves the raw telegram, such as
+// /api/v1/telegram on HomeWizard P1 meters. A telegram with the same timestamp
+// as the one before it is skipped, so polling faster than the meter sends
+// does not produce duplicates. Failed connections and server errors are
+// retried with backoff, but other statuses, such as 404 for a wrong path or
+// 401 for a device that wants a token, are reported right away.
+pub struct HttpSource {
+    host: String,
+    port: u16,
+    path: String,
+    interval: Duration,
+    timeout: Duration,
+    backoff: Backoff,
+    next_poll: Option<Instant>,
+    last_timestamp: Option<String>,
+    pending: Vec<u8>,
+    position: usize,
+    duplicates: u64,
+}
+
+impl HttpSource {
+    // Only plain http URLs are supported, as these devices serve nothing else
+    // on the local network.
+    pub fn new(url: &str) -> io::Result<HttpSource> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidInput, "expected an http://host[:port]/path URL");
+        let rest = url.strip_prefix("http://").ok_or_else(invalid)?;
+        let (authority, path) = match rest.find('/') {
+            Some(i) => (&rest[..i], &rest[i..]),
+            None => (rest, "/"),
+        };
+        let (host, port) = match authority.rfind(':') {
+            Some(i) if !authority.ends_with(']') => (&authority[..i], authority[i + 1..].parse().map_err(|_| invalid())?),
+            _ => (authority, 80),
+        };
+        if host.is_empty() {
+            return Err(invalid());
+        }
+        Ok(HttpSource {
+            host: host.trim_start_matches('[').trim_end_matches(']').to_owned(),
+            port,
+            path: path.to_owned(),
+            interval: Duration::from_secs(1),
+            timeout: Duration::from_secs(5),
+            backoff: Backoff::default(),
+            next_poll: None,
+            last_timestamp: None,
+            pending: Vec::new(),
+            position: 0,
+            duplicates: 0,
+        })
+    }
+
+    pub fn interval(mut self, interval: Duration) -> HttpSource {
+        self.interval = interval;
+        self
+    }
+
+    // Applies to connecting as well as to each read and write.
+    pub fn timeout(mut self, timeout: Duration) -> HttpSource {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn backoff(mut self, backoff: Backoff) -> HttpSource {
+        self.backoff = backoff;
+        self
+    }
+
+    // The telegrams are checked against their CRC, as they would be when read
+    // from a cable.
+    pub fn into_reader(self) -> DatagramReader<io::BufReader<HttpSource>> {
+        DatagramReader::new(io::BufReader::new(self)).verify_crc(true)
+    }
+
+    // The number of telegrams skipped because they were seen before.
+    pub fn duplicates(&self) -> u64 {
+        self.duplicates
+    }
+
+    // Waits for the next poll and fetches telegrams until a new one arrives.
+    fn poll(&mut self) -> io::Result<()> {
+        loop {
+            if let Some(next_poll) = self.next_poll {
+                let now = Instant::now();
+                if next_poll > now {
+                    thread::sleep(next_poll - now);
+                }
+            }
+            self.next_poll = Some(Instant::now() + self.interval);
+            let mut telegram = self.backoff.retry_if(is_retryable, || self.fetch())?;
+            let timestamp = timestamp(&telegram);
+            if timestamp.is_some() && timestamp == self.last_timestamp {
+                self.duplicates += 1;
+                continue;
+            }
+            self.last_timestamp = timestamp;
+            // Some devices drop the line end after the CRC.
+            if !telegram.ends_with(b"\r\n") {
+                let end = telegram.iter().rposition(|b| !b.is_ascii_whitespace()).map(|i| i + 1).unwrap_or(0);
+                telegram.truncate(end);
+                telegram.extend_from_slice(b"\r\n");
+            }
+            self.pending = telegram;
+            self.position = 0;
+            return Ok(());
+        }
+    }
+
+    fn fetch(&self) -> io::Result<Vec<u8>> {
+        let mut error = io::Error::new(io::ErrorKind::InvalidInput, "host did not resolve");
+        for address in (self.host.as_str(), self.port).to_socket_addrs()? {
+            match TcpStream::connect_timeout(&address, self.timeout) {
+                Ok(stream) => return self.request(stream),
+                Err(e) => error = e,
+            }
+        }
+        Err(error)
+    }
+
+    // HTTP/1.0 keeps servers from using chunked transfer encoding, so the
+    // body is simply everything after the headers.
+    fn request(&self, mut stream: TcpStream) -> io::Result<Vec<u8>> {
+        stream.set_read_timeout(Some(self.timeout))?;
+        stream.set_write_timeout(Some(self.timeout))?;
+        write!(stream, "GET {} HTTP/1.0\r\nHost: {}\r\nAccept: text/plain\r\nConnection: close\r\n\r\n", self.path, self.authority())?;
+        let mut response = Vec::new();
+        stream.take(MAX_RESPONSE_SIZE as u64 + 1).read_to_end(&mut response)?;
+        if response.len() > MAX_RESPONSE_SIZE {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "response too large"));
+        }
+        parse_response(response)
+    }
+
+    // The host as it goes in a Host header, with an IPv6 address in brackets
+    // and the port when it is not the default.
+    fn authority(&self) -> String {
+        let host = if self.host.contains(':') { format!("[{}]", self.host) } else { self.host.clone() };
+        match self.port {
+            80 => host,
+            port => format!("{}:{}", host, port),
+        }
+    }
+}
+
+impl Read for HttpSource {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.position == self.pending.len() {
+            self.poll()?;
+        }
+        let length = cmp::min(buf.len(), self.pending.len() - self.position);
+        buf[..length].copy_from_slice(&self.pending[self.position..self.position + length]);
+        self.position += length;
+        Ok(length)
+    }
+}
+
+fn parse_response(mut response: Vec<u8>) -> io::Result<Vec<u8>> {
+    let invalid = |message| io::Error::new(io::ErrorKind::InvalidData, message);
+    let header_end = response.windows(4).position(|w| w == b"\r\n\r\n").ok_or_else(|| invalid("incomplete response"))?;
+    let status = response.split(|b| *b == b' ').nth(1).and_then(|s| std::str::from_utf8(s).ok()).and_then(|s| s.parse().ok());
+    match status {
+        Some(200) => {},
+        Some(status) => return Err(io::Error::other(UnexpectedStatus(status))),
+        None => return Err(invalid("malformed status line")),
+    }
+    let body = response.split_off(header_end + 4);
+    if body.is_empty() {
+        return Err(invalid("empty response"));
+    }
+    Ok(body)
+}
+
+#[derive(Debug)]
+struct UnexpectedStatus(u16);
+
+impl fmt::Display for UnexpectedStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "unexpected HTTP status {}", self.0)
+    }
+}
+
+impl error::Error for UnexpectedStatus {}
+
+fn is_retryable(error: &io::Error) -> bool {
+    match error.get_ref().and_then(|e| e.downcast_ref::<UnexpectedStatus>()) {
+        Some(&UnexpectedStatus(status)) => status >= 500,
+        None => true,
+    }
+}
+
+fn timestamp(telegram: &[u8]) -> Option<String> {
+    let id = ObisIdentifier::parse("0-0:1.0.0")?;
+    let object = TelegramRef::new(telegram).object(&id)?;
+    let value = object.values().next()?;
+    Some(value.text.to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpListener;
+    use super::super::{CrcError, ReadDatagram};
+
+    fn fast_backoff() -> Backoff {
+        Backoff { initial_delay: Duration::from_millis(1), max_delay: Duration::from_millis(10), max_attempts: Some(3) }
+    }
+
+    // Answers each request with the next response, and returns the paths
+    // that were requested once all responses are sent.
+    fn serve(responses: Vec<(&'static str, Vec<u8>)>) -> (String, thread::JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/api/v1/telegram", listener.local_addr().unwrap());
+        let server = thread::spawn(move || {
+            let mut paths = Vec::new();
+            for (status, body) in responses {
+                let (stream, _) = listener.accept().unwrap();
+                let mut request = BufReader::new(stream);
+                let mut line = String::new();
+                request.read_line(&mut line).unwrap();
+                paths.push(line.split(' ').nth(1).unwrap().to_owned());
+                while line != "\r\n" {
+                    line.clear();
+                    request.read_line(&mut line).unwrap();
+                }
+                let mut stream = request.into_inner();
+                write!(stream, "HTTP/1.0 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\n\r\n", status, body.len()).unwrap();
+                stream.write_all(&body).unwrap();
+            }
+            paths
+        });
+        (url, server)
+    }
+
+    #[test]
+    fn it_should_poll_telegrams_and_skip_duplicates() {
+        let correct_datagram_1: &[u8] = include_bytes!("correct_datagram_1.test");
+        let correct_datagram_2: &[u8] = include_bytes!("correct_datagram_2.test");
+        let mut with_line_end = correct_datagram_2.to_vec();
+        with_line_end.extend_from_slice(b"\r\n");
+        let (url, server) = serve(vec!(
+            ("200 OK", correct_datagram_1.to_vec()),
+            ("200 OK", correct_datagram_1.to_vec()),
+            ("200 OK", with_line_end),
+        ));
+        let mut reader = HttpSource::new(&url).unwrap()
+            .interval(Duration::from_millis(1))
+            .backoff(fast_backoff())
+            .into_reader();
+
+        assert_eq!(reader.next().unwrap(), ReadDatagram::Datagram(correct_datagram_1.to_vec().into_boxed_slice()));
+        assert_eq!(reader.next().unwrap(), ReadDatagram::Datagram(correct_datagram_2.to_vec().into_boxed_slice()));
+        assert_eq!(reader.get_ref().get_ref().duplicates(), 1);
+        assert_eq!(server.join().unwrap(), vec!("/api/v1/telegram"; 3));
+    }
+
+    #[test]
+    fn it_should_verify_the_crc() {
+        let correct_datagram_1: &[u8] = include_bytes!("correct_datagram_1.test");
+        let mut corrupted = correct_datagram_1.to_vec();
+        corrupted[100] = 15;
+        let (url, _server) = serve(vec!(("200 OK", corrupted.clone())));
+        let mut reader = HttpSource::new(&url).unwrap().backoff(fast_backoff()).into_reader();
+
+        assert_eq!(reader.next().unwrap(), ReadDatagram::InvalidCrc {
+            datagram: corrupted.into_boxed_slice(),
+            actual_crc: 0xBAD7,
+            error: CrcError::Mismatch { expected: 0xE47C },
+        });
+    }
+
+    #[test]
+    fn it_should_retry_and_report_server_errors() {
+        let (url, server) = serve(vec!(("503 Service Unavailable", b"busy".to_vec()); 3));
+        let mut reader = HttpSource::new(&url).unwrap().backoff(fast_backoff()).into_reader();
+
+        assert_eq!(reader.next(), None);
+        assert_eq!(reader.take_error().map(|e| e.to_string()), Some("unexpected HTTP status 503".to_owned()));
+        assert_eq!(server.join().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn it_should_report_client_errors_without_retrying() {
+        let correct_datagram_1: &[u8] = include_bytes!("correct_datagram_1.test");
+        let (url, server) = serve(vec!(
+            ("404 Not Found", b"not found".to_vec()),
+            ("200 OK", correct_datagram_1.to_vec()),
+        ));
+        // Would retry forever if the status was taken for a passing failure.
+        let mut reader = HttpSource::new(&url).unwrap().interval(Duration::from_millis(1)).into_reader();
+
+        assert_eq!(reader.next(), None);
+        assert_eq!(reader.take_error().map(|e| e.to_string()), Some("unexpected HTTP status 404".to_owned()));
+        assert_eq!(reader.next().unwrap(), ReadDatagram::Datagram(correct_datagram_1.to_vec().into_boxed_slice()));
+        assert_eq!(server.join().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn it_should_only_accept_http_urls() {
+        assert!(HttpSource::new("https://meter/api/v1/telegram").is_err());
+        assert!(HttpSource::new("http://:80/").is_err());
+        let source = HttpSource::new("http://192.168.1.20").unwrap();
+        assert_eq!((source.host.as_str(), source.port, source.path.as_str()), ("192.168.1.20", 80, "/"));
+        let source = HttpSource::new("http://[::1]:8080/api/v1/telegram").unwrap();
+        assert_eq!((source.host.as_str(), source.port, source.path.as_str()), ("::1", 8080, "/api/v1/telegram"));
+    }
+
+    #[test]
+    fn it_should_send_the_port_and_ipv6_brackets_in_the_host_header() {
+        assert_eq!(HttpSource::new("http://meter/").unwrap().authority(), "meter");
+        assert_eq!(HttpSource::new("http://meter:8080/").unwrap().authority(), "meter:8080");
+        assert_eq!(HttpSource::new("http://[::1]/").unwrap().authority(), "[::1]");
+        assert_eq!(HttpSource::new("http://[fe80::1]:8080/").unwrap().authority(), "[fe80::1]:8080");
+    }
+}
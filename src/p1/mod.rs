@@ -1,57 +1,169 @@
-use std::str;
-use crc::{crc16, Hasher16, CalcType};
+use core::str;
 
+#[cfg(feature = "std")]
+pub mod anonymise;
+#[cfg(feature = "archive")]
+pub mod archive;
+#[cfg(feature = "std")]
+pub mod cadence;
+#[cfg(feature = "std")]
+pub mod capture;
+pub mod crc;
+#[cfg(feature = "std")]
+pub mod decoder;
+#[cfg(feature = "std")]
+pub mod diff;
+pub mod fixed;
+pub mod framing;
+#[cfg(feature = "http")]
+pub mod http;
+#[cfg(feature = "mqtt")]
+pub mod mqtt;
+#[cfg(feature = "std")]
+pub mod parity;
+#[cfg(feature = "std")]
 pub mod reader;
+#[cfg(feature = "std")]
+pub mod repair;
+#[cfg(all(feature = "serial", unix))]
+pub mod serial;
+#[cfg(feature = "std")]
+pub mod stats;
+#[cfg(feature = "tokio")]
+pub mod stream;
+#[cfg(feature = "tcp")]
+pub mod tcp;
+pub mod telegram;
+#[cfg(feature = "std")]
+pub mod timestamp;
 
-#[derive(Debug, PartialEq)]
+#[cfg(feature = "std")]
+pub use self::decoder::P1Decoder;
+pub use self::fixed::FixedDecoder;
+#[cfg(feature = "std")]
+pub use self::repair::repair_crc;
+
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, PartialEq)]
 pub enum ReadDatagram {
     Datagram(Box<[u8]>),
     IncompleteDatagram(Box<[u8]>),
     InvalidCrc {
     	datagram: Box<[u8]>,
-    	expected_crc: Option<u16>,
     	actual_crc: u16,
+    	error: CrcError,
+    },
+    Repaired {
+        datagram: Box<[u8]>,
+        corrected_offset: usize,
     },
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CrcError {
+    Missing,
+    WrongLength,
+    NotHex,
+    Mismatch { expected: u16 },
+    Lowercase,
+    Whitespace,
+    MissingLineEnd,
+}
+
+// By default only the CRC line prescribed by DSMR is accepted: four
+// uppercase hexadecimal digits directly followed by CRLF. The line ending can
+// only be checked by a DatagramReader that verifies CRCs itself. With repair
+// set, datagrams whose CRC does not match are passed through repair_crc.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CrcPolicy {
+    pub allow_lowercase: bool,
+    pub allow_whitespace: bool,
+    pub allow_missing_line_end: bool,
+    pub repair: bool,
+}
+
+#[cfg(feature = "std")]
 pub fn verify_crc(datagram: ReadDatagram) -> ReadDatagram {
+    verify_crc_with(datagram, &CrcPolicy::default())
+}
+
+#[cfg(feature = "std")]
+pub fn verify_crc_with(datagram: ReadDatagram, policy: &CrcPolicy) -> ReadDatagram {
     match datagram {
-        ReadDatagram::Datagram(data) => verify_datagram_crc(data),
-        x @ _ => x,
+        ReadDatagram::Datagram(data) => verify_datagram_crc(data, policy),
+        x => x,
     }
 }
 
-fn verify_datagram_crc(datagram: Box<[u8]>) -> ReadDatagram {
-    let (actual_crc, expected_crc) = {
-        let data = &datagram[..datagram.len() - 4];
-        let mut digest = crc16::Digest::new_custom(crc16::USB, 0u16, 0u16, CalcType::Reverse);
-        digest.write(data);
-        let actual_crc = digest.sum16();
-        
-        let expected_crc = &datagram[datagram.len() - 4..];
-        let expected_crc = parse_crc_text(expected_crc);
-        (actual_crc, expected_crc)
-    };
-
-    match expected_crc {
-        Some(expected_crc) if expected_crc == actual_crc => ReadDatagram::Datagram(datagram),
-        _ => ReadDatagram::InvalidCrc { datagram, expected_crc, actual_crc }
+#[cfg(feature = "std")]
+fn verify_datagram_crc(datagram: Box<[u8]>, policy: &CrcPolicy) -> ReadDatagram {
+    let crc_start = crc_text_start(&datagram);
+    let actual_crc = crc::checksum(&datagram[..crc_start]);
+    check_datagram_crc(datagram, actual_crc, policy, true)
+}
+
+// The CRC text starts after the last '!', or is missing when there is none.
+#[cfg(feature = "std")]
+fn crc_text_start(datagram: &[u8]) -> usize {
+    datagram.iter().rposition(|b| *b == b'!').map(|i| i + 1).unwrap_or(datagram.len())
+}
+
+// Compares the CRC text at the end of the datagram with a CRC that was
+// already computed over the data before it.
+#[cfg(feature = "std")]
+fn check_datagram_crc(datagram: Box<[u8]>, actual_crc: u16, policy: &CrcPolicy, line_end: bool) -> ReadDatagram {
+    match classify_crc_text(&datagram[crc_text_start(&datagram)..], actual_crc, policy, line_end) {
+        Ok(()) => ReadDatagram::Datagram(datagram),
+        Err(error) => {
+            let datagram = ReadDatagram::InvalidCrc { datagram, actual_crc, error };
+            if policy.repair { repair_crc(datagram) } else { datagram }
+        },
+    }
+}
+
+// A checksum mismatch takes precedence over formatting problems, so those
+// are only reported for CRC lines that carry the right value.
+fn classify_crc_text(text: &[u8], actual_crc: u16, policy: &CrcPolicy, line_end: bool) -> Result<(), CrcError> {
+    let trimmed = trim_whitespace(text);
+    if trimmed.is_empty() {
+        return Err(CrcError::Missing);
+    }
+    if !trimmed.iter().all(|b| b.is_ascii_hexdigit()) {
+        return Err(CrcError::NotHex);
+    }
+    if trimmed.len() != 4 {
+        return Err(CrcError::WrongLength);
+    }
+    let expected = parse_crc_text(trimmed).ok_or(CrcError::NotHex)?;
+    if expected != actual_crc {
+        Err(CrcError::Mismatch { expected })
+    } else if !policy.allow_lowercase && trimmed.iter().any(|b| b.is_ascii_lowercase()) {
+        Err(CrcError::Lowercase)
+    } else if !policy.allow_whitespace && trimmed.len() != text.len() {
+        Err(CrcError::Whitespace)
+    } else if !policy.allow_missing_line_end && !line_end {
+        Err(CrcError::MissingLineEnd)
+    } else {
+        Ok(())
     }
 }
 
+fn trim_whitespace(text: &[u8]) -> &[u8] {
+    let is_whitespace = |b: &u8| *b == b' ' || *b == b'\t';
+    let start = text.iter().position(|b| !is_whitespace(b)).unwrap_or(text.len());
+    let end = text.iter().rposition(|b| !is_whitespace(b)).map(|i| i + 1).unwrap_or(start);
+    &text[start..end]
+}
+
 fn parse_crc_text(crc: &[u8]) -> Option<u16> {
     if let Ok(crc) = str::from_utf8(crc) {
-        if let Ok(crc) = u16::from_str_radix(crc, 16) {
-            Some(crc)
-        } else {
-            None
-        }
+        u16::from_str_radix(crc, 16).ok()
     } else {
         None
     }
 }
 
-#[cfg(test)]
+#[cfg(all(test, feature = "std"))]
 mod tests {
     use super::*;
 
@@ -61,7 +173,7 @@ mod tests {
         let mut datagram = Vec::new();
         datagram.extend_from_slice(correct_datagram_1);
 
-        let output = verify_datagram_crc(datagram.into_boxed_slice());
+        let output = verify_datagram_crc(datagram.into_boxed_slice(), &CrcPolicy::default());
         
         let mut expected_datagram = Vec::new();
         expected_datagram.extend_from_slice(correct_datagram_1);
@@ -75,14 +187,50 @@ mod tests {
         datagram.extend_from_slice(correct_datagram_1);
         datagram[100] = 15;
 
-        let output = verify_datagram_crc(datagram.to_owned().into_boxed_slice());
+        let output = verify_datagram_crc(datagram.to_owned().into_boxed_slice(), &CrcPolicy::default());
         
         let expected_output = ReadDatagram::InvalidCrc {
             datagram: datagram.into_boxed_slice(),
             actual_crc: 0xBAD7,
-            expected_crc: Some(0xE47C),
+            error: CrcError::Mismatch { expected: 0xE47C },
         };
         assert_eq!(output, expected_output);
     }
 
+    fn verify_with_crc_text(crc_text: &[u8], policy: &CrcPolicy) -> ReadDatagram {
+        let correct_datagram_1: &[u8] = include_bytes!("correct_datagram_1.test");
+        let mut datagram = Vec::new();
+        datagram.extend_from_slice(&correct_datagram_1[..correct_datagram_1.len() - 4]);
+        datagram.extend_from_slice(crc_text);
+        verify_datagram_crc(datagram.into_boxed_slice(), policy)
+    }
+
+    fn crc_error(datagram: ReadDatagram) -> Option<CrcError> {
+        match datagram {
+            ReadDatagram::InvalidCrc { error, .. } => Some(error),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn it_should_classify_malformed_crc_text() {
+        let policy = CrcPolicy::default();
+        assert_eq!(crc_error(verify_with_crc_text(b"", &policy)), Some(CrcError::Missing));
+        assert_eq!(crc_error(verify_with_crc_text(b"E47", &policy)), Some(CrcError::WrongLength));
+        assert_eq!(crc_error(verify_with_crc_text(b"E47C0", &policy)), Some(CrcError::WrongLength));
+        assert_eq!(crc_error(verify_with_crc_text(b"E4\x007C", &policy)), Some(CrcError::NotHex));
+        assert_eq!(crc_error(verify_with_crc_text(b"E47D", &policy)), Some(CrcError::Mismatch { expected: 0xE47D }));
+    }
+
+    #[test]
+    fn it_should_reject_nonstandard_crc_text_unless_the_policy_allows_it() {
+        let strict = CrcPolicy::default();
+        let lenient = CrcPolicy { allow_lowercase: true, allow_whitespace: true, ..CrcPolicy::default() };
+
+        assert_eq!(crc_error(verify_with_crc_text(b"e47c", &strict)), Some(CrcError::Lowercase));
+        assert_eq!(crc_error(verify_with_crc_text(b" E47C\t", &strict)), Some(CrcError::Whitespace));
+        assert_eq!(crc_error(verify_with_crc_text(b"e47c", &lenient)), None);
+        assert_eq!(crc_error(verify_with_crc_text(b" E47C\t", &lenient)), None);
+        assert_eq!(crc_error(verify_with_crc_text(b"e47d", &lenient)), Some(CrcError::Mismatch { expected: 0xE47D }));
+    }
 }
@@ -0,0 +1,583 @@
+use std::cmp;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::time::{Duration, Instant};
+use super::decoder::DEFAULT_MAX_DATAGRAM_SIZE;
+use super::reader::{Backoff, DatagramReader, Reconnected};
+
+const CONNECT: u8 = 0x10;
+const CONNACK: u8 = 0x20;
+const PUBLISH: u8 = 0x30;
+const PUBACK: u8 = 0x40;
+const SUBSCRIBE: u8 = 0x82;
+const SUBACK: u8 = 0x90;
+const PINGREQ: u8 = 0xC0;
+const DISCONNECT: u8 = 0xE0;
+
+const MAX_PACKET_SIZE: usize = 4 * DEFAULT_MAX_DATAGRAM_SIZE;
+
+// Subscribes to an MQTT topic on which a dongle publishes the raw telegram
+// text, as ESPHome and Tasmota based readers do. Payloads are passed on as a
+// byte stream, so a telegram split across messages is put back together by
+// the reader. Only MQTT 3.1.1 without TLS or authentication is spoken, which
+// is what these devices use on a local broker. When the connection drops, or
+// the broker stops answering pings, it reconnects with backoff.
+pub struct MqttSource {
+    addresses: Vec<SocketAddr>,
+    topic: String,
+    client_id: String,
+    keep_alive: Duration,
+    backoff: Backoff,
+    stream: Option<TcpStream>,
+    received: Vec<u8>,
+    last_sent: Instant,
+    ping_sent: Option<Instant>,
+    pending: Vec<u8>,
+    position: usize,
+    connected: bool,
+    reconnects: u64,
+}
+
+impl MqttSource {
+    // Resolves the broker address right away, but connects on the first read.
+    pub fn new<A: ToSocketAddrs>(broker: A, topic: &str) -> io::Result<MqttSource> {
+        let addresses: Vec<SocketAddr> = broker.to_socket_addrs()?.collect();
+        if addresses.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "address did not resolve"));
+        }
+        Ok(MqttSource {
+            addresses,
+            topic: topic.to_owned(),
+            client_id: format!("power-monitor-{}", std::process::id()),
+            keep_alive: Duration::from_secs(30),
+            backoff: Backoff::default(),
+            stream: None,
+            received: Vec::new(),
+            last_sent: Instant::now(),
+            ping_sent: None,
+            pending: Vec::new(),
+            position: 0,
+            connected: false,
+            reconnects: 0,
+        })
+    }
+
+    pub fn client_id(mut self, client_id: &str) -> MqttSource {
+        self.client_id = client_id.to_owned();
+        self
+    }
+
+    // The broker is pinged when nothing was sent to it for this long, as
+    // brokers close connections that stay quiet for longer. The connection is
+    // considered stalled when nothing arrives for as long again after a ping.
+    // MQTT counts it in whole seconds.
+    pub fn keep_alive(mut self, keep_alive: Duration) -> MqttSource {
+        assert!(keep_alive.as_secs() > 0 && keep_alive.as_secs() <= u16::MAX as u64, "keep alive must be between 1 and 65535 seconds");
+        self.keep_alive = keep_alive;
+        self
+    }
+
+    pub fn backoff(mut self, backoff: Backoff) -> MqttSource {
+        self.backoff = backoff;
+        self
+    }
+
+    pub fn into_reader(self) -> DatagramReader<io::BufReader<MqttSource>> {
+        DatagramReader::new(io::BufReader::new(self)).verify_crc(true)
+    }
+
+    // The number of times the connection was made again after the first.
+    pub fn reconnects(&self) -> u64 {
+        self.reconnects
+    }
+
+    fn connect(&mut self) -> io::Result<()> {
+        self.stream = Some(self.backoff.retry(|| self.open())?);
+        self.received.clear();
+        self.last_sent = Instant::now();
+        self.ping_sent = None;
+        if self.connected {
+            self.reconnects += 1;
+        }
+        self.connected = true;
+        Ok(())
+    }
+
+    fn open(&self) -> io::Result<TcpStream> {
+        let mut error = io::Error::new(io::ErrorKind::InvalidInput, "no addresses");
+        for address in &self.addresses {
+            match TcpStream::connect_timeout(address, self.keep_alive) {
+                Ok(stream) => return self.handshake(stream),
+                Err(e) => error = e,
+            }
+        }
+        Err(error)
+    }
+
+    fn handshake(&self, mut stream: TcpStream) -> io::Result<TcpStream> {
+        stream.set_read_timeout(Some(self.keep_alive))?;
+        stream.set_write_timeout(Some(self.keep_alive))?;
+
+        let mut connect = Vec::new();
+        put_string(&mut connect, "MQTT");
+        // Protocol level 4 is MQTT 3.1.1, with a clean session.
+        connect.extend_from_slice(&[4, 0x02]);
+        connect.extend_from_slice(&(self.keep_alive.as_secs() as u16).to_be_bytes());
+        put_string(&mut connect, &self.client_id);
+        write_packet(&mut stream, CONNECT, &connect)?;
+        match read_packet(&mut stream)? {
+            (CONNACK, ref body) if body.len() == 2 && body[1] == 0 => {},
+            (CONNACK, body) => return Err(refused(format!("connection refused with code {}", body.get(1).cloned().unwrap_or(0)))),
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "expected CONNACK")),
+        }
+
+        let mut subscribe = vec!(0, 1);
+        put_string(&mut subscribe, &self.topic);
+        subscribe.push(0);
+        write_packet(&mut stream, SUBSCRIBE, &subscribe)?;
+        match read_packet(&mut stream)? {
+            (SUBACK, ref body) if body.len() == 3 && body[2] != 0x80 => {},
+            (SUBACK, _) => return Err(refused("subscription refused".to_owned())),
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "expected SUBACK")),
+        }
+        Ok(stream)
+    }
+
+    // Reads packets until a message arrives and returns its payload.
+    fn receive(&mut self) -> io::Result<Vec<u8>> {
+        loop {
+            if self.stream.is_none() {
+                let reconnect = self.connected;
+                self.connect()?;
+                if reconnect {
+                    return Err(Reconnected::error());
+                }
+            }
+            let (header, body) = match self.next_packet() {
+                Ok(Some(packet)) => packet,
+                Ok(None) => continue,
+                Err(_) => {
+                    self.stream = None;
+                    continue;
+                },
+            };
+            if header & 0xF0 != PUBLISH {
+                continue;
+            }
+            match self.publish(header, body) {
+                Ok(payload) => return Ok(payload),
+                Err(_) => self.stream = None,
+            }
+        }
+    }
+
+    fn next_packet(&mut self) -> io::Result<Option<(u8, Vec<u8>)>> {
+        self.next_packet_at(Instant::now())
+    }
+
+    // Returns the next packet, or None when a read timed out. Bytes of a
+    // packet that has not fully arrived are kept for the next call. The read
+    // timeout is set so a ping goes out once keep_alive passed since the last
+    // packet was sent, even while messages keep arriving.
+    fn next_packet_at(&mut self, now: Instant) -> io::Result<Option<(u8, Vec<u8>)>> {
+        if let Some((header, body, size)) = parse_packet(&self.received)? {
+            self.received.drain(..size);
+            self.ping_sent = None;
+            return Ok(Some((header, body)));
+        }
+        let deadline = match self.ping_sent {
+            Some(ping_sent) if now >= ping_sent + self.keep_alive => {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "the broker stopped answering pings"));
+            },
+            Some(ping_sent) => ping_sent + self.keep_alive,
+            None if now >= self.last_sent + self.keep_alive => {
+                self.send_at(now, PINGREQ, &[])?;
+                self.ping_sent = Some(now);
+                now + self.keep_alive
+            },
+            None => self.last_sent + self.keep_alive,
+        };
+        let stream = match self.stream {
+            Some(ref mut stream) => stream,
+            None => return Ok(None),
+        };
+        stream.set_read_timeout(Some(cmp::max(deadline - now, Duration::from_millis(1))))?;
+        let mut buffer = [0u8; 4096];
+        match stream.read(&mut buffer) {
+            Ok(0) => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "the broker closed the connection")),
+            Ok(length) => {
+                self.received.extend_from_slice(&buffer[..length]);
+                Ok(None)
+            },
+            Err(ref e) if is_timeout(e) || e.kind() == io::ErrorKind::Interrupted => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn send(&mut self, header: u8, body: &[u8]) -> io::Result<()> {
+        self.send_at(Instant::now(), header, body)
+    }
+
+    fn send_at(&mut self, now: Instant, header: u8, body: &[u8]) -> io::Result<()> {
+        if let Some(ref mut stream) = self.stream {
+            write_packet(stream, header, body)?;
+            self.last_sent = now;
+        }
+        Ok(())
+    }
+
+    fn publish(&mut self, header: u8, body: Vec<u8>) -> io::Result<Vec<u8>> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "malformed PUBLISH");
+        let topic_length = body.get(..2).map(|b| u16::from_be_bytes([b[0], b[1]]) as usize).ok_or_else(invalid)?;
+        let mut offset = 2 + topic_length;
+        let qos = (header >> 1) & 0x03;
+        if qos > 0 {
+            let id = body.get(offset..offset + 2).ok_or_else(invalid)?.to_vec();
+            offset += 2;
+            // The subscription asks for QoS 0, but brokers may still deliver
+            // retained messages at a higher level.
+            if qos == 1 {
+                self.send(PUBACK, &id)?;
+            }
+        }
+        if offset > body.len() {
+            return Err(invalid());
+        }
+        Ok(body[offset..].to_vec())
+    }
+}
+
+impl Read for MqttSource {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        while self.position == self.pending.len() {
+            self.pending = self.receive()?;
+            self.position = 0;
+            // Dongles that publish a whole telegram per message tend to leave
+            // out the line end after the CRC.
+            if ends_with_crc(&self.pending) {
+                self.pending.extend_from_slice(b"\r\n");
+            }
+        }
+        let length = cmp::min(buf.len(), self.pending.len() - self.position);
+        buf[..length].copy_from_slice(&self.pending[self.position..self.position + length]);
+        self.position += length;
+        Ok(length)
+    }
+}
+
+impl Drop for MqttSource {
+    fn drop(&mut self) {
+        if let Some(ref mut stream) = self.stream {
+            let _ = write_packet(stream, DISCONNECT, &[]);
+        }
+    }
+}
+
+fn ends_with_crc(payload: &[u8]) -> bool {
+    payload.len() >= 5 && payload[payload.len() - 5] == b'!' && payload[payload.len() - 4..].iter().all(|b| b.is_ascii_hexdigit())
+}
+
+fn is_timeout(error: &io::Error) -> bool {
+    matches!(error.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut)
+}
+
+fn refused(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::ConnectionRefused, message)
+}
+
+fn put_string(packet: &mut Vec<u8>, text: &str) {
+    packet.extend_from_slice(&(text.len() as u16).to_be_bytes());
+    packet.extend_from_slice(text.as_bytes());
+}
+
+fn write_packet<W: Write>(writer: &mut W, header: u8, body: &[u8]) -> io::Result<()> {
+    let mut packet = vec!(header);
+    let mut length = body.len();
+    loop {
+        let byte = (length % 128) as u8;
+        length /= 128;
+        if length == 0 {
+            packet.push(byte);
+            break;
+        }
+        packet.push(byte | 0x80);
+    }
+    packet.extend_from_slice(body);
+    writer.write_all(&packet)
+}
+
+// Returns the packet at the start of the buffer and its size, or None when it
+// has not fully arrived yet.
+fn parse_packet(buffer: &[u8]) -> io::Result<Option<(u8, Vec<u8>, usize)>> {
+    let mut length = 0usize;
+    let mut start = 1;
+    for shift in 0.. {
+        let byte = match buffer.get(start) {
+            Some(byte) => *byte,
+            None => return Ok(None),
+        };
+        start += 1;
+        length = add_length_byte(length, byte, shift)?;
+        if byte & 0x80 == 0 {
+            break;
+        }
+    }
+    if length > MAX_PACKET_SIZE {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "packet too large"));
+    }
+    Ok(buffer.get(start..start + length).map(|body| (buffer[0], body.to_vec(), start + length)))
+}
+
+// The remaining length takes at most four bytes, so a fourth byte that
+// still has the continuation bit set makes the packet malformed.
+fn add_length_byte(length: usize, byte: u8, shift: usize) -> io::Result<usize> {
+    if shift == 3 && byte & 0x80 != 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "malformed remaining length"));
+    }
+    Ok(length | ((byte & 0x7F) as usize) << (7 * shift))
+}
+
+fn read_packet<R: Read>(reader: &mut R) -> io::Result<(u8, Vec<u8>)> {
+    let mut byte = [0u8];
+    reader.read_exact(&mut byte)?;
+    let header = byte[0];
+    let mut length = 0usize;
+    for shift in 0.. {
+        reader.read_exact(&mut byte)?;
+        length = add_length_byte(length, byte[0], shift)?;
+        if byte[0] & 0x80 == 0 {
+            break;
+        }
+    }
+    if length > MAX_PACKET_SIZE {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "packet too large"));
+    }
+    let mut body = vec!(0u8; length);
+    reader.read_exact(&mut body)?;
+    Ok((header, body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+    use super::super::ReadDatagram;
+
+    const PINGRESP: u8 = 0xD0;
+
+    fn fast_backoff() -> Backoff {
+        Backoff { initial_delay: Duration::from_millis(1), max_delay: Duration::from_millis(10), max_attempts: Some(3) }
+    }
+
+    fn publish(topic: &str, payload: &[u8]) -> Vec<u8> {
+        let mut body = Vec::new();
+        put_string(&mut body, topic);
+        body.extend_from_slice(payload);
+        body
+    }
+
+    // Accepts a connection for each list of payloads, publishes them and
+    // closes the connection. Returns the topics that were subscribed to.
+    fn broker(connections: Vec<Vec<Vec<u8>>>) -> (SocketAddr, thread::JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let broker = thread::spawn(move || {
+            let mut topics = Vec::new();
+            for payloads in connections {
+                let (mut stream, _) = listener.accept().unwrap();
+                let (header, connect) = read_packet(&mut stream).unwrap();
+                assert_eq!((header, &connect[..7]), (CONNECT, &b"\x00\x04MQTT\x04"[..]));
+                write_packet(&mut stream, CONNACK, &[0, 0]).unwrap();
+                let (header, subscribe) = read_packet(&mut stream).unwrap();
+                assert_eq!(header, SUBSCRIBE);
+                topics.push(String::from_utf8(subscribe[4..subscribe.len() - 1].to_vec()).unwrap());
+                write_packet(&mut stream, SUBACK, &[0, 1, 0]).unwrap();
+                for payload in payloads {
+                    write_packet(&mut stream, PUBLISH, &publish("p1/telegram", &payload)).unwrap();
+                }
+            }
+            topics
+        });
+        (address, broker)
+    }
+
+    #[test]
+    fn it_should_reassemble_telegrams_split_across_messages() {
+        let correct_datagram_1: &[u8] = include_bytes!("correct_datagram_1.test");
+        let correct_datagram_2: &[u8] = include_bytes!("correct_datagram_2.test");
+        let (address, broker) = broker(vec!(
+            vec!(correct_datagram_1[..300].to_vec(), correct_datagram_1[300..].to_vec()),
+            vec!(correct_datagram_2.to_vec()),
+        ));
+        let mut reader = MqttSource::new(address, "p1/telegram").unwrap().backoff(fast_backoff()).into_reader();
+
+        assert_eq!(reader.next().unwrap(), ReadDatagram::Datagram(correct_datagram_1.to_vec().into_boxed_slice()));
+        assert_eq!(reader.next().unwrap(), ReadDatagram::Datagram(correct_datagram_2.to_vec().into_boxed_slice()));
+        assert_eq!(reader.get_ref().get_ref().reconnects(), 1);
+        assert_eq!(broker.join().unwrap(), vec!("p1/telegram"; 2));
+    }
+
+    #[test]
+    fn it_should_not_join_a_telegram_across_a_reconnect() {
+        let correct_datagram_1: &[u8] = include_bytes!("correct_datagram_1.test");
+        let (address, broker) = broker(vec!(
+            vec!(correct_datagram_1[..300].to_vec()),
+            vec!(correct_datagram_1[300..].to_vec(), correct_datagram_1.to_vec()),
+        ));
+        let mut reader = MqttSource::new(address, "p1/telegram").unwrap().backoff(fast_backoff()).into_reader();
+
+        assert_eq!(reader.next().unwrap(), ReadDatagram::IncompleteDatagram(correct_datagram_1[..300].to_vec().into_boxed_slice()));
+        assert_eq!(reader.next().unwrap(), ReadDatagram::Datagram(correct_datagram_1.to_vec().into_boxed_slice()));
+        broker.join().unwrap();
+    }
+
+    fn accept(listener: &TcpListener) -> TcpStream {
+        let (mut stream, _) = listener.accept().unwrap();
+        read_packet(&mut stream).unwrap();
+        write_packet(&mut stream, CONNACK, &[0, 0]).unwrap();
+        read_packet(&mut stream).unwrap();
+        write_packet(&mut stream, SUBACK, &[0, 1, 0]).unwrap();
+        stream
+    }
+
+    // Returns the next packet read with the clock at now, which only goes
+    // forward when a test moves it.
+    fn next_packet_at(source: &mut MqttSource, now: Instant) -> (u8, Vec<u8>) {
+        loop {
+            if let Some(packet) = source.next_packet_at(now).unwrap() {
+                return packet;
+            }
+        }
+    }
+
+    #[test]
+    fn it_should_ping_the_broker_while_messages_arrive() {
+        let correct_datagram_1: &[u8] = include_bytes!("correct_datagram_1.test");
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let payload = publish("p1/telegram", correct_datagram_1);
+        // Publishes a message, and one more after each ping it answers.
+        let broker = thread::spawn(move || {
+            let mut stream = accept(&listener);
+            write_packet(&mut stream, PUBLISH, &payload).unwrap();
+            let mut pings = 0;
+            while let Ok((PINGREQ, _)) = read_packet(&mut stream) {
+                pings += 1;
+                write_packet(&mut stream, PINGRESP, &[]).unwrap();
+                write_packet(&mut stream, PUBLISH, &payload).unwrap();
+            }
+            pings
+        });
+        let mut source = MqttSource::new(address, "p1/telegram").unwrap().backoff(fast_backoff());
+        source.connect().unwrap();
+        let (connected, keep_alive) = (source.last_sent, source.keep_alive);
+
+        // Messages keep arriving, but the ping is still due once nothing was
+        // sent for the keep alive.
+        assert_eq!(next_packet_at(&mut source, connected).0, PUBLISH);
+        let ping_due = connected + keep_alive;
+        assert_eq!(next_packet_at(&mut source, ping_due).0, PINGRESP);
+        assert_eq!(next_packet_at(&mut source, ping_due).0, PUBLISH);
+        assert_eq!(next_packet_at(&mut source, ping_due + keep_alive).0, PINGRESP);
+
+        drop(source);
+        assert_eq!(broker.join().unwrap(), 2);
+    }
+
+    #[test]
+    fn it_should_report_a_broker_that_stops_answering_pings() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        // Answers the ping with the start of a packet that never completes.
+        let broker = thread::spawn(move || {
+            let mut stream = accept(&listener);
+            assert_eq!(read_packet(&mut stream).unwrap().0, PINGREQ);
+            stream.write_all(&[PUBLISH]).unwrap();
+            while read_packet(&mut stream).is_ok() {}
+        });
+        let mut source = MqttSource::new(address, "p1/telegram").unwrap().backoff(fast_backoff());
+        source.connect().unwrap();
+        let ping_due = source.last_sent + source.keep_alive;
+
+        while source.received.is_empty() {
+            assert_eq!(source.next_packet_at(ping_due).unwrap(), None);
+        }
+        let error = source.next_packet_at(ping_due + source.keep_alive).unwrap_err();
+
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+        drop(source);
+        broker.join().unwrap();
+    }
+
+    #[test]
+    fn it_should_keep_a_packet_that_arrives_across_a_ping() {
+        let correct_datagram_1: &[u8] = include_bytes!("correct_datagram_1.test");
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let body = publish("p1/telegram", correct_datagram_1);
+        let mut packet = Vec::new();
+        write_packet(&mut packet, PUBLISH, &body).unwrap();
+        let broker = thread::spawn(move || {
+            let mut stream = accept(&listener);
+            // The rest of the packet only follows the ping.
+            stream.write_all(&packet[..300]).unwrap();
+            assert_eq!(read_packet(&mut stream).unwrap().0, PINGREQ);
+            stream.write_all(&packet[300..]).unwrap();
+            while read_packet(&mut stream).is_ok() {}
+        });
+        let mut source = MqttSource::new(address, "p1/telegram").unwrap().backoff(fast_backoff());
+        source.connect().unwrap();
+        let (connected, keep_alive) = (source.last_sent, source.keep_alive);
+
+        while source.received.len() < 300 {
+            assert_eq!(source.next_packet_at(connected).unwrap(), None);
+        }
+        let packet = next_packet_at(&mut source, connected + keep_alive);
+
+        assert_eq!(packet, (PUBLISH, body));
+        drop(source);
+        broker.join().unwrap();
+    }
+
+    #[test]
+    fn it_should_reject_a_remaining_length_longer_than_four_bytes() {
+        let packet = [PUBLISH, 0x80, 0x80, 0x80, 0x80, 0x01];
+
+        assert_eq!(parse_packet(&packet).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(read_packet(&mut &packet[..]).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(parse_packet(&packet[..4]).unwrap(), None);
+    }
+
+    #[test]
+    fn it_should_report_a_refused_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            for _ in 0..3 {
+                let (mut stream, _) = listener.accept().unwrap();
+                read_packet(&mut stream).unwrap();
+                // Not authorised.
+                write_packet(&mut stream, CONNACK, &[0, 5]).unwrap();
+            }
+        });
+        let mut reader = MqttSource::new(address, "p1/telegram").unwrap().backoff(fast_backoff()).into_reader();
+
+        assert_eq!(reader.next(), None);
+        assert_eq!(reader.take_error().map(|e| e.kind()), Some(io::ErrorKind::ConnectionRefused));
+    }
+
+    #[test]
+    fn it_should_encode_and_decode_packet_lengths() {
+        let body = vec!(7u8; 20_000);
+        let mut packet = Vec::new();
+
+        write_packet(&mut packet, PUBLISH, &body).unwrap();
+
+        assert_eq!(&packet[..4], &[PUBLISH, 0xA0, 0x9C, 0x01]);
+        assert_eq!(read_packet(&mut &packet[..]).unwrap(), (PUBLISH, body));
+    }
+}
@@ -0,0 +1,95 @@
+// DSMR 2.2 and 3 meters send 7E1. Cables that only do 8N1 deliver those bytes
+// with the parity bit in bit 7, which hides every '/' and '!' from framing.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Parity {
+    // Bytes are used as they arrive.
+    #[default]
+    None,
+    // Bit 7 is an even parity bit, which is checked and stripped.
+    Even,
+    // Decides between None and Even from the bytes that arrive.
+    Auto,
+}
+
+// Returns the byte without its parity bit, and whether the parity was right.
+pub fn strip_even_parity(byte: u8) -> (u8, bool) {
+    (byte & 0x7F, byte.count_ones().is_multiple_of(2))
+}
+
+const WINDOW: u32 = 128;
+
+// Telegrams are printable ASCII. Read as 8N1 they never have bit 7 set, while
+// 7E1 sets it on about half of them and always yields even parity. Noise, such
+// as data at the wrong baud rate, has odd parity on about half of the bytes,
+// so a window that fits neither case is discarded.
+#[derive(Debug, Clone, Default)]
+pub(super) struct Detector {
+    bytes: u32,
+    high_bytes: u32,
+    parity_errors: u32,
+}
+
+impl Detector {
+    // The number of bytes until the next decision can be made.
+    pub(super) fn remaining(&self) -> usize {
+        (WINDOW - self.bytes) as usize
+    }
+
+    pub(super) fn observe(&mut self, bytes: &[u8]) -> Option<Parity> {
+        for byte in bytes {
+            self.bytes += 1;
+            if byte & 0x80 != 0 {
+                self.high_bytes += 1;
+            }
+            if !strip_even_parity(*byte).1 {
+                self.parity_errors += 1;
+            }
+            if self.bytes == WINDOW {
+                let decision = if self.high_bytes * 4 >= self.bytes && self.parity_errors * 16 <= self.bytes {
+                    Some(Parity::Even)
+                } else if self.high_bytes == 0 {
+                    Some(Parity::None)
+                } else {
+                    None
+                };
+                *self = Detector::default();
+                if decision.is_some() {
+                    return decision;
+                }
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn with_even_parity(byte: u8) -> u8 {
+        if byte.count_ones().is_multiple_of(2) { byte } else { byte | 0x80 }
+    }
+
+    #[test]
+    fn it_should_strip_and_check_even_parity() {
+        assert_eq!(strip_even_parity(0xAF), (b'/', true));
+        assert_eq!(strip_even_parity(b'0'), (b'0', true));
+        assert_eq!(strip_even_parity(b'/'), (b'/', false));
+    }
+
+    #[test]
+    fn it_should_detect_7e1_read_as_8n1() {
+        let correct_datagram_1: &[u8] = include_bytes!("correct_datagram_1.test");
+        let encoded: Vec<u8> = correct_datagram_1.iter().map(|b| with_even_parity(*b)).collect();
+
+        assert_eq!(Detector::default().observe(&encoded), Some(Parity::Even));
+        assert_eq!(Detector::default().observe(correct_datagram_1), Some(Parity::None));
+    }
+
+    #[test]
+    fn it_should_not_decide_on_noise() {
+        let noise: Vec<u8> = (0..1024u32).map(|i| (i.wrapping_mul(2_654_435_761) >> 13) as u8).collect();
+
+        assert_eq!(Detector::default().observe(&noise), None);
+    }
+}
@@ -1,111 +1,319 @@
+use std::cmp;
+use std::error;
+use std::fmt;
 use std::io;
-use super::ReadDatagram;
+use std::thread;
+use std::time::Duration;
+use super::{CrcPolicy, ReadDatagram};
+use super::decoder::P1Decoder;
+use super::stats::{Snapshot, Statistics};
+
+pub use super::decoder::DEFAULT_MAX_DATAGRAM_SIZE;
+pub use super::framing::Framing;
+pub use super::parity::Parity;
+pub use super::timestamp::{Received, Timestamp};
 
 pub struct DatagramReader<R> {
     reader: R,
-    error: Option<io::Error>
+    error: Option<io::Error>,
+    retry_policy: RetryPolicy,
+    decoder: P1Decoder,
+    statistics: Statistics,
 }
 
-impl<R: io::BufRead> DatagramReader<R> {
-    pub fn new(reader: R) -> DatagramReader<R> {
-        DatagramReader {
-            reader,
-            error: None
+#[derive(Debug)]
+pub enum ReaderEvent {
+    Datagram(ReadDatagram, Received),
+    Garbage(Box<[u8]>),
+    Oversized(Box<[u8]>),
+    IoError(io::Error),
+    Eof,
+}
+
+// Interrupted, TimedOut and WouldBlock errors are retried up to max_retries
+// times in a row, waiting delay in between. Other errors and transient errors
+// beyond that are reported, but the reader keeps its framing state so the
+// next read resumes where it left off.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    pub delay: Duration,
+}
+
+impl RetryPolicy {
+    pub fn is_transient(error: &io::Error) -> bool {
+        matches!(error.kind(), io::ErrorKind::Interrupted | io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock)
+    }
+
+    fn should_retry(&self, error: &io::Error, retries: u32) -> bool {
+        RetryPolicy::is_transient(error) && retries < self.max_retries
+    }
+}
+
+
+// Used by sources to reconnect. The first attempt is made right away, and the
+// delay after each failure doubles, up to max_delay. Reconnecting is given up after
+// max_attempts failures in a row, or never when it is None.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Backoff {
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+    pub max_attempts: Option<u32>,
+}
+
+impl Default for Backoff {
+    fn default() -> Backoff {
+        Backoff {
+            initial_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(30),
+            max_attempts: None,
         }
     }
+}
+
+impl Backoff {
+    pub fn retry<T, F: FnMut() -> io::Result<T>>(&self, attempt: F) -> io::Result<T> {
+        self.retry_if(|_| true, attempt)
+    }
 
-    fn sync_to_datagram(&mut self) -> io::Result<usize> {
-        let mut read = 0;
+    // Like retry, but errors for which retryable returns false are reported
+    // right away.
+    pub fn retry_if<T, P: Fn(&io::Error) -> bool, F: FnMut() -> io::Result<T>>(&self, retryable: P, mut attempt: F) -> io::Result<T> {
+        let mut delay = self.initial_delay;
+        let mut attempts = 0;
         loop {
-            let (available_bytes, dropped_bytes) = {
-                let available = self.reader.fill_buf()?;
-                (available.len(), available.into_iter().take_while(|b| **b != b'/').count())
-            };
-            if available_bytes == 0 {
-                return Ok(0);
-            }
-            self.reader.consume(dropped_bytes);
-            if dropped_bytes < available_bytes {
-                return Ok(read + dropped_bytes);
+            match attempt() {
+                Ok(value) => return Ok(value),
+                Err(e) => {
+                    attempts += 1;
+                    if !retryable(&e) || self.max_attempts.is_some_and(|max| attempts >= max) {
+                        return Err(e);
+                    }
+                    thread::sleep(delay);
+                    delay = cmp::min(delay * 2, self.max_delay);
+                },
             }
-            read += dropped_bytes;
         }
     }
+}
 
-    fn read_datagram(&mut self) -> io::Result<Vec<u8>> {
-        if self.reader.fill_buf()?.len() == 0 {
-            return Ok(Vec::new());
-        } else {
-            self.reader.consume(1);
-        }
-        let mut datagram = vec![b'/'];
-        loop {
-            let (available_bytes, read_bytes) = {
-                let available = self.reader.fill_buf()?;
-                let datagram_bytes = available.iter().take_while(|b| **b != b'/' && **b != b'!').count();
-                datagram.extend_from_slice(&available[0..datagram_bytes]);
-                (available.len(), datagram_bytes)
-            };
-            self.reader.consume(read_bytes);
-            if available_bytes == 0 || read_bytes < available_bytes {
-                return Ok(datagram);
-            }
+// Returned once by a source after it reconnected. The reader then ends the
+// datagram it was reading, so bytes from before and after the gap are not
+// joined. Its kind is Interrupted, so plain readers simply retry.
+#[derive(Debug)]
+pub struct Reconnected;
+
+impl Reconnected {
+    pub fn error() -> io::Error {
+        io::Error::new(io::ErrorKind::Interrupted, Reconnected)
+    }
+
+    pub fn is(error: &io::Error) -> bool {
+        error.get_ref().is_some_and(|e| e.is::<Reconnected>())
+    }
+}
+
+impl fmt::Display for Reconnected {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("the source reconnected")
+    }
+}
+
+impl error::Error for Reconnected {}
+
+impl<R> DatagramReader<R> {
+    pub fn get_ref(&self) -> &R {
+        &self.reader
+    }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.reader
+    }
+
+    // Returns the underlying reader, dropping any partially framed datagram.
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+}
+
+impl<R: io::BufRead> DatagramReader<R> {
+    pub fn new(reader: R) -> DatagramReader<R> {
+        DatagramReader::with_decoder(reader, P1Decoder::new())
+    }
+
+    pub fn with_decoder(reader: R, decoder: P1Decoder) -> DatagramReader<R> {
+        DatagramReader {
+            reader,
+            error: None,
+            retry_policy: RetryPolicy::default(),
+            decoder,
+            statistics: Statistics::default(),
         }
     }
 
-    fn read_crc_bytes(&mut self, datagram: &mut Vec<u8>) -> io::Result<()> {
-        if self.reader.fill_buf()?.len() == 0 {
-            return Ok(());
+    // These configure the decoder, see P1Decoder.
+    pub fn verify_crc(mut self, verify_crc: bool) -> DatagramReader<R> {
+        self.decoder = self.decoder.verify_crc(verify_crc);
+        self
+    }
+
+    pub fn crc_policy(mut self, crc_policy: CrcPolicy) -> DatagramReader<R> {
+        self.decoder = self.decoder.crc_policy(crc_policy);
+        self
+    }
+
+    pub fn max_datagram_size(mut self, max_datagram_size: usize) -> DatagramReader<R> {
+        self.decoder = self.decoder.max_datagram_size(max_datagram_size);
+        self
+    }
+
+    pub fn framing(mut self, framing: Framing) -> DatagramReader<R> {
+        self.decoder = self.decoder.framing(framing);
+        self
+    }
+
+    pub fn parity(mut self, parity: Parity) -> DatagramReader<R> {
+        self.decoder = self.decoder.parity(parity);
+        self
+    }
+
+    pub fn retry_policy(mut self, retry_policy: RetryPolicy) -> DatagramReader<R> {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    pub fn statistics_window(mut self, window: Duration) -> DatagramReader<R> {
+        self.statistics = Statistics::new(window);
+        self
+    }
+
+    pub fn statistics(&self) -> Snapshot {
+        self.statistics.snapshot()
+    }
+
+    // Returns the error that ended the last iteration, if any.
+    pub fn take_error(&mut self) -> Option<io::Error> {
+        self.error.take()
+    }
+
+    pub fn events(&mut self) -> Events<'_, R> {
+        Events { reader: self, done: false }
+    }
+
+    // Like iterating the reader itself, but with the time each datagram
+    // arrived.
+    pub fn timestamped(&mut self) -> Timestamped<'_, R> {
+        Timestamped { reader: self }
+    }
+
+    // Reads up to and including the next datagram. Bytes skipped before it
+    // are reported first as a separate Garbage event.
+    pub fn next_event(&mut self) -> ReaderEvent {
+        let event = self.read_event();
+        match event {
+            ReaderEvent::Datagram(ref datagram, _) => self.statistics.record_datagram(datagram),
+            ReaderEvent::Garbage(ref garbage) => self.statistics.record_garbage(garbage.len()),
+            ReaderEvent::Oversized(_) => self.statistics.record_oversized(),
+            _ => {},
         }
-        let mut crc_bytes_needed = 5;
+        event
+    }
+
+    fn next_datagram(&mut self) -> Option<(ReadDatagram, Received)> {
         loop {
-            let (available_bytes, read_bytes) = {
-                let available = self.reader.fill_buf()?;
-                let crc_bytes = available.iter().take(crc_bytes_needed).take_while(|b| **b != b'/').count();
-                datagram.extend_from_slice(&available[0..crc_bytes]);
-                (available.len(), crc_bytes)
-            };
-            self.reader.consume(read_bytes);
-            crc_bytes_needed -= read_bytes;
-            if available_bytes == 0 || read_bytes < available_bytes || crc_bytes_needed == 0 {
-                return Ok(());
+            match self.next_event() {
+                ReaderEvent::Datagram(d, received) => return Some((d, received)),
+                ReaderEvent::Garbage(_) | ReaderEvent::Oversized(_) => {},
+                ReaderEvent::IoError(e) => { self.error = Some(e); return None },
+                ReaderEvent::Eof => return None,
             }
         }
     }
 
-    fn next_datagram(&mut self) -> io::Result<ReadDatagram> {
-        let _dropped_bytes = self.sync_to_datagram()?;
-        let mut datagram = self.read_datagram()?;
-        {
-            let available = self.reader.fill_buf()?;
-            if available.len() == 0 || available[0] == b'/' {
-                return Ok(ReadDatagram::IncompleteDatagram(datagram.into_boxed_slice()));
+    fn read_event(&mut self) -> ReaderEvent {
+        let mut retries = 0;
+        loop {
+            let (consumed, event) = match self.reader.fill_buf() {
+                Ok([]) => return self.decoder.finish().unwrap_or(ReaderEvent::Eof),
+                Ok(available) => {
+                    retries = 0;
+                    self.decoder.process(available)
+                },
+                Err(ref e) if Reconnected::is(e) => match self.decoder.finish() {
+                    Some(event) => return event,
+                    None => continue,
+                },
+                Err(e) => {
+                    self.statistics.record_io_error();
+                    if !self.retry_policy.should_retry(&e, retries) {
+                        return ReaderEvent::IoError(e);
+                    }
+                    retries += 1;
+                    thread::sleep(self.retry_policy.delay);
+                    continue;
+                },
+            };
+            self.reader.consume(consumed);
+            self.statistics.record_bytes_read(consumed);
+            self.statistics.record_parity_errors(self.decoder.take_parity_errors());
+            if let Some(event) = event {
+                return event;
             }
         }
-        self.read_crc_bytes(&mut datagram)?;
-        if datagram[datagram.len() - 5] == b'!' {
-            Ok(ReadDatagram::Datagram(datagram.into_boxed_slice()))
-        } else {
-            Ok(ReadDatagram::IncompleteDatagram(datagram.into_boxed_slice()))
-        }
     }
 }
 
 impl<R: io::BufRead> Iterator for DatagramReader<R> {
     type Item = ReadDatagram;
 
+    // Skips garbage and oversized datagrams, which only show up in the
+    // statistics. Ends at the end of the input or at an I/O error, which is then
+    // available through take_error. Calling it again resumes reading.
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_datagram().map(|(d, _)| d)
+    }
+}
+
+pub struct Timestamped<'a, R: 'a> {
+    reader: &'a mut DatagramReader<R>,
+}
+
+impl<'a, R: io::BufRead> Iterator for Timestamped<'a, R> {
+    type Item = (ReadDatagram, Received);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.reader.next_datagram()
+    }
+}
+
+// Yields reader events up to and including the first Eof or IoError.
+pub struct Events<'a, R: 'a> {
+    reader: &'a mut DatagramReader<R>,
+    done: bool,
+}
+
+impl<'a, R: io::BufRead> Iterator for Events<'a, R> {
+    type Item = ReaderEvent;
+
     fn next(&mut self) -> Option<Self::Item> {
-        match self.next_datagram() {
-            Ok(ReadDatagram::IncompleteDatagram(ref d)) if d.len() == 0 => None,
-            Ok(d) => Some(d),
-            Err(e) => { self.error = Some(e); None },
+        if self.done {
+            return None;
+        }
+        let event = self.reader.next_event();
+        match event {
+            ReaderEvent::IoError(_) | ReaderEvent::Eof => self.done = true,
+            _ => {},
         }
+        Some(event)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use super::super::CrcError;
+    use super::super::crc::checksum;
+    use super::super::stats::Counters;
 
     pub struct TickleReader<'a> {
         data: &'a [u8],
@@ -113,6 +321,7 @@ mod tests {
     }
 
     impl<'a> io::Read for TickleReader<'a> {
+        #[allow(clippy::needless_range_loop)]
         fn read(&mut self, b: &mut [u8]) -> io::Result<usize> {
             if self.ranges.is_empty() {
                 Ok(0)
@@ -150,6 +359,453 @@ mod tests {
         assert_eq!(datagram.unwrap(), ReadDatagram::Datagram(expected_datagram.into_boxed_slice()));
     }
 
+    #[test]
+    fn it_should_verify_the_crc_while_reading() {
+        let correct_datagram_1: &[u8] = include_bytes!("correct_datagram_1.test");
+        let mut combined_input: Vec<u8> = Vec::new();
+        combined_input.extend_from_slice(correct_datagram_1);
+        combined_input.extend_from_slice(b"\r\n");
+        combined_input.extend_from_slice(correct_datagram_1);
+        combined_input[correct_datagram_1.len() + 102] = 15;
+        let tickler = TickleReader {
+            data: combined_input.as_slice(),
+            ranges: vec!([0, 44], [44, 544], [544, 1500], [1500, combined_input.len()]),
+        };
+        let mut reader = DatagramReader::new(io::BufReader::with_capacity(1, tickler)).verify_crc(true);
+
+        let mut expected_datagram = Vec::new();
+        expected_datagram.extend_from_slice(correct_datagram_1);
+        assert_eq!(reader.next().unwrap(), ReadDatagram::Datagram(expected_datagram.into_boxed_slice()));
+
+        let expected_output = ReadDatagram::InvalidCrc {
+            datagram: combined_input[correct_datagram_1.len() + 2..].to_vec().into_boxed_slice(),
+            actual_crc: 0xBAD7,
+            error: CrcError::Mismatch { expected: 0xE47C },
+        };
+        assert_eq!(reader.next().unwrap(), expected_output);
+    }
+
+    #[test]
+    fn it_should_reject_a_crc_line_without_crlf_unless_the_policy_allows_it() {
+        let correct_datagram_1: &[u8] = include_bytes!("correct_datagram_1.test");
+        let mut combined_input: Vec<u8> = Vec::new();
+        combined_input.extend_from_slice(correct_datagram_1);
+        combined_input.extend_from_slice(b"\r\n");
+        combined_input.extend_from_slice(correct_datagram_1);
+        combined_input.extend_from_slice(b"\n");
+
+        let mut reader = DatagramReader::new(io::BufReader::new(combined_input.as_slice())).verify_crc(true);
+        assert_eq!(reader.next().unwrap(), ReadDatagram::Datagram(correct_datagram_1.to_vec().into_boxed_slice()));
+        assert_eq!(reader.next().unwrap(), ReadDatagram::InvalidCrc {
+            datagram: correct_datagram_1.to_vec().into_boxed_slice(),
+            actual_crc: 0xE47C,
+            error: CrcError::MissingLineEnd,
+        });
+
+        let policy = CrcPolicy { allow_missing_line_end: true, ..CrcPolicy::default() };
+        let mut reader = DatagramReader::new(io::BufReader::new(combined_input.as_slice())).verify_crc(true).crc_policy(policy);
+        assert_eq!(reader.next().unwrap(), ReadDatagram::Datagram(correct_datagram_1.to_vec().into_boxed_slice()));
+        assert_eq!(reader.next().unwrap(), ReadDatagram::Datagram(correct_datagram_1.to_vec().into_boxed_slice()));
+    }
+
+    #[test]
+    fn it_should_read_a_crc_line_with_lowercase_hex_and_whitespace() {
+        let correct_datagram_1: &[u8] = include_bytes!("correct_datagram_1.test");
+        let mut input: Vec<u8> = Vec::new();
+        input.extend_from_slice(&correct_datagram_1[..correct_datagram_1.len() - 4]);
+        input.extend_from_slice(b"e47c \r\n");
+        let policy = CrcPolicy { allow_lowercase: true, allow_whitespace: true, ..CrcPolicy::default() };
+        let mut reader = DatagramReader::new(io::BufReader::new(input.as_slice())).verify_crc(true).crc_policy(policy);
+
+        assert_eq!(reader.next().unwrap(), ReadDatagram::Datagram(input[..input.len() - 2].to_vec().into_boxed_slice()));
+        assert_eq!(reader.next(), None);
+    }
+
+    struct FailingReader<'a> {
+        data: &'a [u8],
+    }
+
+    impl<'a> io::Read for FailingReader<'a> {
+        fn read(&mut self, b: &mut [u8]) -> io::Result<usize> {
+            if self.data.is_empty() {
+                Err(io::Error::new(io::ErrorKind::NotConnected, "device went away"))
+            } else {
+                let len = self.data.len().min(b.len());
+                b[..len].copy_from_slice(&self.data[..len]);
+                self.data = &self.data[len..];
+                Ok(len)
+            }
+        }
+    }
+
+    #[test]
+    fn it_should_report_garbage_datagrams_and_eof_as_events() {
+        let correct_datagram_1: &[u8] = include_bytes!("correct_datagram_1.test");
+        let mut combined_input: Vec<u8> = Vec::new();
+        combined_input.extend_from_slice(&[4, 23, 32]);
+        combined_input.extend_from_slice(correct_datagram_1);
+        combined_input.extend_from_slice(&[4, 75]);
+        let mut reader = DatagramReader::new(io::BufReader::with_capacity(1, combined_input.as_slice()));
+
+        let events: Vec<ReaderEvent> = reader.events().collect();
+
+        assert_eq!(events.len(), 4);
+        match events[0] {
+            ReaderEvent::Garbage(ref garbage) => assert_eq!(**garbage, [4, 23, 32]),
+            ref event => panic!("unexpected event {:?}", event),
+        }
+        match events[1] {
+            ReaderEvent::Datagram(ref datagram, _) => assert_eq!(*datagram, ReadDatagram::Datagram(correct_datagram_1.to_vec().into_boxed_slice())),
+            ref event => panic!("unexpected event {:?}", event),
+        }
+        match events[2] {
+            ReaderEvent::Garbage(ref garbage) => assert_eq!(**garbage, [4, 75]),
+            ref event => panic!("unexpected event {:?}", event),
+        }
+        match events[3] {
+            ReaderEvent::Eof => {},
+            ref event => panic!("unexpected event {:?}", event),
+        }
+    }
+
+    #[test]
+    fn it_should_tell_an_io_error_apart_from_eof() {
+        let correct_datagram_1: &[u8] = include_bytes!("correct_datagram_1.test");
+        let mut reader = DatagramReader::new(io::BufReader::new(FailingReader { data: correct_datagram_1 }));
+
+        let events: Vec<ReaderEvent> = reader.events().collect();
+
+        assert_eq!(events.len(), 1);
+        match events[0] {
+            ReaderEvent::IoError(ref e) => assert_eq!(e.kind(), io::ErrorKind::NotConnected),
+            ref event => panic!("unexpected event {:?}", event),
+        }
+
+        let mut reader = DatagramReader::new(io::BufReader::new(FailingReader { data: b"" }));
+        assert_eq!(reader.next(), None);
+        assert_eq!(reader.take_error().map(|e| e.kind()), Some(io::ErrorKind::NotConnected));
+    }
+
+    struct FlakyReader<'a> {
+        data: &'a [u8],
+        position: usize,
+        errors: Vec<(usize, io::ErrorKind)>,
+    }
+
+    impl<'a> io::Read for FlakyReader<'a> {
+        fn read(&mut self, b: &mut [u8]) -> io::Result<usize> {
+            if let Some(&(offset, kind)) = self.errors.first() {
+                if offset == self.position {
+                    self.errors.remove(0);
+                    return Err(io::Error::new(kind, "flaky"));
+                }
+            }
+            let end = self.errors.first().map(|e| e.0).unwrap_or(self.data.len()).min(self.position + b.len());
+            let len = end - self.position;
+            b[..len].copy_from_slice(&self.data[self.position..end]);
+            self.position = end;
+            Ok(len)
+        }
+    }
+
+    #[test]
+    fn it_should_retry_transient_errors() {
+        let correct_datagram_1: &[u8] = include_bytes!("correct_datagram_1.test");
+        let flaky = FlakyReader {
+            data: correct_datagram_1,
+            position: 0,
+            errors: vec!((0, io::ErrorKind::WouldBlock), (100, io::ErrorKind::TimedOut), (100, io::ErrorKind::Interrupted)),
+        };
+        let policy = RetryPolicy { max_retries: 2, delay: Duration::from_millis(1) };
+        let mut reader = DatagramReader::new(io::BufReader::with_capacity(16, flaky)).retry_policy(policy);
+
+        assert_eq!(reader.next().unwrap(), ReadDatagram::Datagram(correct_datagram_1.to_vec().into_boxed_slice()));
+        assert!(reader.take_error().is_none());
+    }
+
+    #[test]
+    fn it_should_only_wait_after_a_failed_attempt() {
+        let backoff = Backoff { initial_delay: Duration::from_millis(50), max_delay: Duration::from_millis(50), max_attempts: Some(3) };
+        let start = std::time::Instant::now();
+        assert_eq!(backoff.retry(|| Ok(1)).unwrap(), 1);
+        assert!(start.elapsed() < Duration::from_millis(50));
+
+        let mut attempts = 0;
+        let start = std::time::Instant::now();
+        let result: io::Result<()> = backoff.retry(|| {
+            attempts += 1;
+            Err(io::Error::other("failed"))
+        });
+        assert!(result.is_err());
+        assert_eq!(attempts, 3);
+        assert!(start.elapsed() >= Duration::from_millis(100));
+    }
+
+    #[test]
+    fn it_should_keep_the_partial_datagram_across_an_error() {
+        let correct_datagram_1: &[u8] = include_bytes!("correct_datagram_1.test");
+        let flaky = FlakyReader {
+            data: correct_datagram_1,
+            position: 0,
+            errors: vec!((300, io::ErrorKind::BrokenPipe), (correct_datagram_1.len() - 2, io::ErrorKind::TimedOut)),
+        };
+        let mut reader = DatagramReader::new(io::BufReader::with_capacity(16, flaky));
+
+        assert_eq!(reader.next(), None);
+        assert_eq!(reader.take_error().map(|e| e.kind()), Some(io::ErrorKind::BrokenPipe));
+        assert_eq!(reader.get_ref().get_ref().position, 300);
+
+        assert_eq!(reader.next(), None);
+        assert_eq!(reader.take_error().map(|e| e.kind()), Some(io::ErrorKind::TimedOut));
+        reader.get_mut().get_mut().errors.clear();
+
+        assert_eq!(reader.next().unwrap(), ReadDatagram::Datagram(correct_datagram_1.to_vec().into_boxed_slice()));
+        assert_eq!(reader.next(), None);
+        assert!(reader.take_error().is_none());
+        assert_eq!(reader.into_inner().into_inner().position, correct_datagram_1.len());
+    }
+
+    #[test]
+    fn it_should_count_datagrams_garbage_errors_and_bytes() {
+        let correct_datagram_1: &[u8] = include_bytes!("correct_datagram_1.test");
+        let mut combined_input: Vec<u8> = Vec::new();
+        combined_input.extend_from_slice(correct_datagram_1);
+        combined_input.extend_from_slice(&[4, 23, 32]);
+        combined_input.extend_from_slice(correct_datagram_1);
+        combined_input[correct_datagram_1.len() + 103] ^= 0x04;
+        combined_input.extend_from_slice(&correct_datagram_1[..100]);
+        let flaky = FlakyReader {
+            data: combined_input.as_slice(),
+            position: 0,
+            errors: vec!((50, io::ErrorKind::TimedOut)),
+        };
+        let mut reader = DatagramReader::new(io::BufReader::new(flaky)).verify_crc(true)
+            .crc_policy(CrcPolicy { allow_missing_line_end: true, repair: true, ..CrcPolicy::default() });
+
+        while reader.next().is_some() || reader.take_error().is_some() {}
+
+        let statistics = reader.statistics();
+        assert_eq!(statistics.totals, Counters {
+            datagrams: 2,
+            incomplete_datagrams: 1,
+            crc_failures: 1,
+            repaired_datagrams: 1,
+            oversized_datagrams: 0,
+            garbage_bytes: 3,
+            parity_errors: 0,
+            io_errors: 1,
+            bytes_read: combined_input.len() as u64,
+        });
+        assert_eq!(statistics.recent, statistics.totals);
+    }
+
+    #[test]
+    fn it_should_report_an_oversized_datagram_and_resynchronise() {
+        let correct_datagram_1: &[u8] = include_bytes!("correct_datagram_1.test");
+        let mut combined_input: Vec<u8> = Vec::new();
+        combined_input.extend_from_slice(b"/XMX5");
+        combined_input.extend_from_slice(&[b'x'; 3000]);
+        combined_input.extend_from_slice(b"!E47C\r\n");
+        combined_input.extend_from_slice(correct_datagram_1);
+        let mut reader = DatagramReader::new(io::BufReader::with_capacity(64, combined_input.as_slice())).max_datagram_size(2048);
+
+        let events: Vec<ReaderEvent> = reader.events().collect();
+
+        assert_eq!(events.len(), 4);
+        match events[0] {
+            ReaderEvent::Oversized(ref datagram) => assert_eq!(**datagram, combined_input[..2048]),
+            ref event => panic!("unexpected event {:?}", event),
+        }
+        match events[1] {
+            ReaderEvent::Garbage(ref garbage) => assert_eq!(**garbage, combined_input[2048..3012]),
+            ref event => panic!("unexpected event {:?}", event),
+        }
+        match events[2] {
+            ReaderEvent::Datagram(ref datagram, _) => assert_eq!(*datagram, ReadDatagram::Datagram(correct_datagram_1.to_vec().into_boxed_slice())),
+            ref event => panic!("unexpected event {:?}", event),
+        }
+        assert_eq!(reader.statistics().totals.oversized_datagrams, 1);
+    }
+
+    #[test]
+    fn it_should_report_endless_garbage_in_bounded_chunks() {
+        let input = vec![0x55u8; 10000];
+        let mut reader = DatagramReader::new(io::BufReader::new(input.as_slice())).max_datagram_size(4096);
+
+        let lengths: Vec<usize> = reader.events().map(|event| match event {
+            ReaderEvent::Garbage(garbage) => garbage.len(),
+            ReaderEvent::Eof => 0,
+            event => panic!("unexpected event {:?}", event),
+        }).collect();
+
+        assert_eq!(lengths, vec!(4096, 4096, 1808, 0));
+    }
+
+    fn datagram_with_slash_in_identification() -> Vec<u8> {
+        let correct_datagram_1: &[u8] = include_bytes!("correct_datagram_1.test");
+        let mut datagram = correct_datagram_1[..correct_datagram_1.len() - 4].to_vec();
+        datagram[12] = b'/';
+        let crc = format!("{:04X}", checksum(&datagram));
+        datagram.extend_from_slice(crc.as_bytes());
+        datagram
+    }
+
+    #[test]
+    fn it_should_only_start_a_datagram_at_a_slash_at_line_start() {
+        let datagram = datagram_with_slash_in_identification();
+        let tickler = TickleReader {
+            data: datagram.as_slice(),
+            ranges: vec!([0, 12], [12, 13], [13, datagram.len()]),
+        };
+        let mut reader = DatagramReader::new(io::BufReader::with_capacity(1, tickler)).framing(Framing::LineStart).verify_crc(true)
+            .crc_policy(CrcPolicy { allow_missing_line_end: true, ..CrcPolicy::default() });
+
+        assert_eq!(reader.next().unwrap(), ReadDatagram::Datagram(datagram.clone().into_boxed_slice()));
+        assert_eq!(reader.next(), None);
+
+        let mut reader = DatagramReader::new(io::BufReader::new(datagram.as_slice()));
+        assert_eq!(reader.next().unwrap(), ReadDatagram::IncompleteDatagram(datagram[..12].to_vec().into_boxed_slice()));
+    }
+
+    #[test]
+    fn it_should_read_the_crc_line_up_to_its_line_ending_at_line_start() {
+        let correct_datagram_1: &[u8] = include_bytes!("correct_datagram_1.test");
+        let correct_datagram_2: &[u8] = include_bytes!("correct_datagram_2.test");
+        let mut combined_input: Vec<u8> = Vec::new();
+        combined_input.extend_from_slice(&correct_datagram_1[..correct_datagram_1.len() - 4]);
+        combined_input.extend_from_slice(b"/E47C\r\n");
+        combined_input.extend_from_slice(correct_datagram_2);
+        let mut reader = DatagramReader::new(io::BufReader::with_capacity(1, combined_input.as_slice())).framing(Framing::LineStart).verify_crc(true);
+
+        assert_eq!(reader.next().unwrap(), ReadDatagram::InvalidCrc {
+            datagram: combined_input[..correct_datagram_1.len() + 1].to_vec().into_boxed_slice(),
+            actual_crc: 0xE47C,
+            error: CrcError::NotHex,
+        });
+        assert_eq!(reader.next().unwrap(), ReadDatagram::InvalidCrc {
+            datagram: correct_datagram_2.to_vec().into_boxed_slice(),
+            actual_crc: 0x6C8D,
+            error: CrcError::MissingLineEnd,
+        });
+    }
+
+    #[test]
+    fn it_should_combine_tickling_data_into_a_single_datagram_at_line_start() {
+        let correct_datagram_1: &[u8] = include_bytes!("correct_datagram_1.test");
+        let tickler = TickleReader {
+            data: correct_datagram_1,
+            ranges: vec!([0, 5], [5, 17], [17, 19], [19, 44], [44, 544], [544, 763], [763, correct_datagram_1.len()]),
+        };
+        let mut reader = DatagramReader::new(io::BufReader::with_capacity(1, tickler)).framing(Framing::LineStart);
+
+        assert_eq!(reader.next().unwrap(), ReadDatagram::Datagram(correct_datagram_1.to_vec().into_boxed_slice()));
+    }
+
+    #[test]
+    fn it_should_combine_tickling_data_with_a_split_in_the_middle_of_crc_into_a_single_datagram_at_line_start() {
+        let correct_datagram_1: &[u8] = include_bytes!("correct_datagram_1.test");
+        let tickler = TickleReader {
+            data: correct_datagram_1,
+            ranges: vec!([0, 44], [44, correct_datagram_1.len() - 3], [correct_datagram_1.len() - 3, correct_datagram_1.len()]),
+        };
+        let mut reader = DatagramReader::new(io::BufReader::with_capacity(1, tickler)).framing(Framing::LineStart);
+
+        assert_eq!(reader.next().unwrap(), ReadDatagram::Datagram(correct_datagram_1.to_vec().into_boxed_slice()));
+    }
+
+    #[test]
+    fn it_should_split_tickling_data_between_carriage_return_and_line_feed_at_line_start() {
+        let correct_datagram_1: &[u8] = include_bytes!("correct_datagram_1.test");
+        let correct_datagram_2: &[u8] = include_bytes!("correct_datagram_2.test");
+        let mut combined_input: Vec<u8> = Vec::new();
+        combined_input.extend_from_slice(correct_datagram_1);
+        combined_input.extend_from_slice(b"\r\n");
+        combined_input.extend_from_slice(correct_datagram_2);
+        let length = correct_datagram_1.len();
+        let tickler = TickleReader {
+            data: combined_input.as_slice(),
+            ranges: vec!([0, length + 1], [length + 1, length + 2], [length + 2, combined_input.len()]),
+        };
+        let mut reader = DatagramReader::new(io::BufReader::with_capacity(1, tickler)).framing(Framing::LineStart);
+
+        assert_eq!(reader.next().unwrap(), ReadDatagram::Datagram(correct_datagram_1.to_vec().into_boxed_slice()));
+        assert_eq!(reader.next().unwrap(), ReadDatagram::Datagram(correct_datagram_2.to_vec().into_boxed_slice()));
+    }
+
+    #[test]
+    fn it_should_signal_an_incomplete_datagram_if_a_new_datagram_starts_at_line_start() {
+        let correct_datagram_1: &[u8] = include_bytes!("correct_datagram_1.test");
+        let correct_datagram_2: &[u8] = include_bytes!("correct_datagram_2.test");
+        let truncated = correct_datagram_1[..200].windows(2).rposition(|w| w == b"\r\n").unwrap() + 2;
+        let mut combined_input: Vec<u8> = Vec::new();
+        combined_input.extend_from_slice(&correct_datagram_1[..truncated]);
+        combined_input.extend_from_slice(correct_datagram_2);
+        let tickler = TickleReader {
+            data: combined_input.as_slice(),
+            ranges: vec!([0, truncated], [truncated, combined_input.len()]),
+        };
+        let mut reader = DatagramReader::new(io::BufReader::with_capacity(1, tickler)).framing(Framing::LineStart);
+
+        assert_eq!(reader.next().unwrap(), ReadDatagram::IncompleteDatagram(correct_datagram_1[..truncated].to_vec().into_boxed_slice()));
+        assert_eq!(reader.next().unwrap(), ReadDatagram::Datagram(correct_datagram_2.to_vec().into_boxed_slice()));
+    }
+
+    #[test]
+    fn it_should_not_start_a_datagram_at_a_slash_within_a_line() {
+        let correct_datagram_1: &[u8] = include_bytes!("correct_datagram_1.test");
+        let correct_datagram_2: &[u8] = include_bytes!("correct_datagram_2.test");
+        let mut combined_input: Vec<u8> = Vec::new();
+        combined_input.extend_from_slice(b"noise/noise\r\n");
+        combined_input.extend_from_slice(correct_datagram_1);
+        combined_input.extend_from_slice(b"\r\n");
+        combined_input.extend_from_slice(correct_datagram_2);
+        let mut reader = DatagramReader::new(io::BufReader::with_capacity(1, combined_input.as_slice())).framing(Framing::LineStart);
+
+        let events: Vec<ReaderEvent> = reader.events().collect();
+
+        assert_eq!(events.len(), 4);
+        match events[0] {
+            ReaderEvent::Garbage(ref garbage) => assert_eq!(**garbage, *b"noise/noise\r\n"),
+            ref event => panic!("unexpected event {:?}", event),
+        }
+    }
+
+    fn with_even_parity(data: &[u8]) -> Vec<u8> {
+        data.iter().map(|b| if b.count_ones().is_multiple_of(2) { *b } else { *b | 0x80 }).collect()
+    }
+
+    #[test]
+    fn it_should_strip_even_parity_and_count_parity_errors() {
+        let correct_datagram_1: &[u8] = include_bytes!("correct_datagram_1.test");
+        let mut input = with_even_parity(correct_datagram_1);
+        input[100] ^= 0x80;
+        let mut reader = DatagramReader::new(io::BufReader::new(input.as_slice())).parity(Parity::Even).verify_crc(true)
+            .crc_policy(CrcPolicy { allow_missing_line_end: true, ..CrcPolicy::default() });
+
+        assert_eq!(reader.next().unwrap(), ReadDatagram::Datagram(correct_datagram_1.to_vec().into_boxed_slice()));
+        assert_eq!(reader.statistics().totals.parity_errors, 1);
+    }
+
+    #[test]
+    fn it_should_detect_even_parity_automatically() {
+        let correct_datagram_1: &[u8] = include_bytes!("correct_datagram_1.test");
+        let correct_datagram_2: &[u8] = include_bytes!("correct_datagram_2.test");
+        let mut combined_input: Vec<u8> = Vec::new();
+        combined_input.extend_from_slice(correct_datagram_1);
+        combined_input.extend_from_slice(b"\r\n");
+        combined_input.extend_from_slice(correct_datagram_2);
+        let input = with_even_parity(&combined_input);
+        let mut reader = DatagramReader::new(io::BufReader::new(input.as_slice())).parity(Parity::Auto);
+
+        assert_eq!(reader.next().unwrap(), ReadDatagram::Datagram(correct_datagram_1.to_vec().into_boxed_slice()));
+        assert_eq!(reader.next().unwrap(), ReadDatagram::Datagram(correct_datagram_2.to_vec().into_boxed_slice()));
+        assert_eq!(reader.statistics().totals.parity_errors, 0);
+
+        let mut reader = DatagramReader::new(io::BufReader::new(combined_input.as_slice())).parity(Parity::Auto);
+        assert_eq!(reader.next().unwrap(), ReadDatagram::Datagram(correct_datagram_1.to_vec().into_boxed_slice()));
+        assert_eq!(reader.next().unwrap(), ReadDatagram::Datagram(correct_datagram_2.to_vec().into_boxed_slice()));
+    }
+
     #[test]
     fn it_should_split_an_input_of_two_datagrams_in_two_outputs() {
         let correct_datagram_1: &[u8] = include_bytes!("correct_datagram_1.test");
@@ -325,4 +981,50 @@ mod tests {
         let datagram = reader.next();
         assert_eq!(datagram.unwrap(), ReadDatagram::Datagram(expected_datagram.into_boxed_slice()));
     }
+
+    // Pauses before every read after the first.
+    struct PausingReader<'a> {
+        parts: Vec<&'a [u8]>,
+        pause: Duration,
+        started: bool,
+    }
+
+    impl<'a> io::Read for PausingReader<'a> {
+        fn read(&mut self, b: &mut [u8]) -> io::Result<usize> {
+            if self.parts.is_empty() {
+                return Ok(0);
+            }
+            if self.started {
+                thread::sleep(self.pause);
+            }
+            self.started = true;
+            let part = self.parts.remove(0);
+            b[..part.len()].copy_from_slice(part);
+            Ok(part.len())
+        }
+    }
+
+    #[test]
+    fn it_should_attach_receive_times_to_datagrams() {
+        let correct_datagram_1: &[u8] = include_bytes!("correct_datagram_1.test");
+        let crc = correct_datagram_1.iter().rposition(|b| *b == b'!').unwrap();
+        let pausing = PausingReader {
+            parts: vec!(&correct_datagram_1[..crc], &correct_datagram_1[crc..]),
+            pause: Duration::from_millis(20),
+            started: false,
+        };
+        let before = Timestamp::now();
+        let mut reader = DatagramReader::new(io::BufReader::new(pausing));
+
+        let (datagram, received) = reader.timestamped().next().unwrap();
+        let after = Timestamp::now();
+
+        assert_eq!(datagram, ReadDatagram::Datagram(correct_datagram_1.to_vec().into_boxed_slice()));
+        let crc_byte = received.crc_byte.unwrap();
+        assert!(received.first_byte.instant >= before.instant);
+        assert!(crc_byte.instant - received.first_byte.instant >= Duration::from_millis(20));
+        assert!(crc_byte.instant <= after.instant);
+        assert!(received.first_byte.time <= crc_byte.time);
+        assert_eq!(reader.timestamped().next(), None);
+    }
 }
@@ -0,0 +1,198 @@
+use super::{crc, crc_text_start, trim_whitespace, CrcError, ReadDatagram};
+use super::telegram::Telegram;
+
+const HEX_DIGITS: &[u8; 16] = b"0123456789ABCDEF";
+
+// Tries to correct a single corrupted byte in a datagram with an invalid CRC.
+// Single bit errors are tried first, as they are the most common on a noisy
+// line and leave the fewest ambiguities. Datagrams are only repaired when
+// exactly one correction yields a valid CRC and a well formed telegram.
+pub fn repair_crc(datagram: ReadDatagram) -> ReadDatagram {
+    match datagram {
+        ReadDatagram::InvalidCrc { datagram, actual_crc, error } => {
+            let expected_crc = match error {
+                CrcError::Mismatch { expected } => Some(expected),
+                CrcError::NotHex => None,
+                _ => return ReadDatagram::InvalidCrc { datagram, actual_crc, error },
+            };
+            match repair(&datagram, expected_crc, actual_crc) {
+                Some((repaired, corrected_offset)) => ReadDatagram::Repaired {
+                    datagram: repaired.into_boxed_slice(),
+                    corrected_offset,
+                },
+                None => ReadDatagram::InvalidCrc { datagram, actual_crc, error },
+            }
+        },
+        x => x,
+    }
+}
+
+fn repair(datagram: &[u8], expected_crc: Option<u16>, actual_crc: u16) -> Option<(Vec<u8>, usize)> {
+    let diagnostics = Telegram::parse(datagram).1.len();
+    let mut candidates = crc_text_candidates(datagram, actual_crc);
+    if let Some(expected_crc) = expected_crc {
+        let data_candidates = data_candidates(datagram, expected_crc ^ actual_crc);
+        let single_bit = |&(offset, byte): &(usize, u8)| (datagram[offset] ^ byte).count_ones() == 1;
+        if data_candidates.iter().any(single_bit) {
+            candidates.extend(data_candidates.into_iter().filter(single_bit));
+        } else {
+            candidates.extend(data_candidates);
+        }
+    }
+
+    let mut repaired = None;
+    for (offset, byte) in candidates {
+        let mut candidate = datagram.to_vec();
+        candidate[offset] = byte;
+        if Telegram::parse(&candidate).1.len() > diagnostics {
+            continue;
+        }
+        if repaired.is_some() {
+            return None;
+        }
+        repaired = Some((candidate, offset));
+    }
+    repaired
+}
+
+// The CRC is linear, so the difference between the expected and the actual
+// CRC only depends on the error. Walking back from the end of the data finds
+// every position where a single byte error explains that difference.
+fn data_candidates(datagram: &[u8], syndrome: u16) -> Vec<(usize, u8)> {
+    let data_length = crc_text_start(datagram);
+    let mut candidates = Vec::new();
+    let mut syndrome = syndrome;
+    for offset in (0..data_length).rev() {
+        let framing = offset == 0 || offset == data_length - 1;
+        if let Some(error) = crc::byte_for(syndrome).filter(|_| !framing) {
+            let byte = datagram[offset] ^ error;
+            if is_telegram_character(byte) {
+                candidates.push((offset, byte));
+            }
+        }
+        syndrome = crc::unshift_zero_byte(syndrome);
+    }
+    candidates
+}
+
+// The CRC text may be surrounded by whitespace and followed by the line end,
+// which are skipped here the same way classify_crc_text skips them.
+fn crc_text_candidates(datagram: &[u8], actual_crc: u16) -> Vec<(usize, u8)> {
+    let start = crc_text_start(datagram);
+    let line = &datagram[start..];
+    let text = trim_whitespace(line.strip_suffix(b"\r\n").unwrap_or(line));
+    if text.len() != 4 {
+        return Vec::new();
+    }
+    let text_start = start + line.iter().take_while(|b| **b == b' ' || **b == b'\t').count();
+    let actual_text: Vec<u8> = (0..4).map(|i| HEX_DIGITS[((actual_crc >> (12 - 4 * i)) & 0xF) as usize]).collect();
+    let differences: Vec<usize> = (0..4).filter(|i| text[*i] != actual_text[*i]).collect();
+    match differences.as_slice() {
+        [i] => vec![(text_start + i, actual_text[*i])],
+        _ => Vec::new(),
+    }
+}
+
+// Telegrams consist of printable ASCII and line endings. The framing
+// characters cannot occur inside the datagram.
+fn is_telegram_character(byte: u8) -> bool {
+    match byte {
+        b'/' | b'!' => false,
+        b'\r' | b'\n' => true,
+        _ => (0x20..0x7F).contains(&byte),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::{verify_crc, verify_crc_with, CrcPolicy};
+
+    fn corrupted(edit: &dyn Fn(&mut Vec<u8>)) -> ReadDatagram {
+        let correct_datagram_1: &[u8] = include_bytes!("correct_datagram_1.test");
+        let mut datagram = correct_datagram_1.to_vec();
+        edit(&mut datagram);
+        verify_crc(ReadDatagram::Datagram(datagram.into_boxed_slice()))
+    }
+
+    fn correct_datagram_1() -> Box<[u8]> {
+        let correct_datagram_1: &[u8] = include_bytes!("correct_datagram_1.test");
+        correct_datagram_1.to_vec().into_boxed_slice()
+    }
+
+    #[test]
+    fn it_should_repair_a_single_bit_error() {
+        let output = repair_crc(corrupted(&|d| d[100] ^= 0x04));
+
+        assert_eq!(output, ReadDatagram::Repaired { datagram: correct_datagram_1(), corrected_offset: 100 });
+    }
+
+    #[test]
+    fn it_should_repair_a_single_bit_error_in_a_line_ending() {
+        let output = repair_crc(corrupted(&|d| d[19] ^= 0x01));
+
+        assert_eq!(output, ReadDatagram::Repaired { datagram: correct_datagram_1(), corrected_offset: 19 });
+    }
+
+    #[test]
+    fn it_should_repair_a_single_character_error_in_the_crc() {
+        let output = repair_crc(corrupted(&|d| {
+            let length = d.len();
+            d[length - 2] = b'G';
+        }));
+
+        let offset = correct_datagram_1().len() - 2;
+        assert_eq!(output, ReadDatagram::Repaired { datagram: correct_datagram_1(), corrected_offset: offset });
+    }
+
+    #[test]
+    fn it_should_repair_crc_text_with_whitespace_or_a_line_end() {
+        let correct_datagram_1 = correct_datagram_1();
+        let data = &correct_datagram_1[..correct_datagram_1.len() - 4];
+        for (prefix, suffix) in [(&b" "[..], &b"\t"[..]), (&b""[..], &b"\r\n"[..])] {
+            let mut datagram = data.to_vec();
+            datagram.extend_from_slice(prefix);
+            let offset = datagram.len() + 2;
+            datagram.extend_from_slice(b"E4GC");
+            datagram.extend_from_slice(suffix);
+
+            let output = repair_crc(verify_crc(ReadDatagram::Datagram(datagram.clone().into_boxed_slice())));
+
+            datagram[offset] = b'7';
+            assert_eq!(output, ReadDatagram::Repaired { datagram: datagram.into_boxed_slice(), corrected_offset: offset });
+        }
+    }
+
+    #[test]
+    fn it_should_repair_when_the_crc_policy_asks_for_it() {
+        let datagram = || {
+            let mut datagram = correct_datagram_1().into_vec();
+            datagram[100] ^= 0x04;
+            ReadDatagram::Datagram(datagram.into_boxed_slice())
+        };
+
+        let repaired = verify_crc_with(datagram(), &CrcPolicy { repair: true, ..CrcPolicy::default() });
+
+        assert_eq!(repaired, ReadDatagram::Repaired { datagram: correct_datagram_1(), corrected_offset: 100 });
+        assert!(matches!(verify_crc(datagram()), ReadDatagram::InvalidCrc { .. }));
+    }
+
+    #[test]
+    fn it_should_not_repair_a_datagram_with_two_errors() {
+        let edit = |d: &mut Vec<u8>| {
+            d[100] ^= 0x04;
+            d[300] ^= 0x10;
+        };
+
+        let output = repair_crc(corrupted(&edit));
+
+        assert_eq!(output, corrupted(&edit));
+    }
+
+    #[test]
+    fn it_should_pass_through_a_correct_datagram() {
+        let output = repair_crc(ReadDatagram::Datagram(correct_datagram_1()));
+
+        assert_eq!(output, ReadDatagram::Datagram(correct_datagram_1()));
+    }
+}
@@ -0,0 +1,270 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use libc;
+use super::reader::{Backoff, DatagramReader, Reconnected};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DataFormat {
+    // 8 data bits, no parity, 1 stop bit.
+    EightNone,
+    // 7 data bits, even parity, 1 stop bit.
+    SevenEven,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LineSettings {
+    pub baud_rate: u32,
+    pub data_format: DataFormat,
+}
+
+impl LineSettings {
+    // DSMR 4 and 5 meters send at 115200 baud, 8N1.
+    pub fn dsmr4() -> LineSettings {
+        LineSettings { baud_rate: 115_200, data_format: DataFormat::EightNone }
+    }
+
+    // DSMR 2.2 and 3 meters send at 9600 baud, 7E1.
+    pub fn dsmr2() -> LineSettings {
+        LineSettings { baud_rate: 9600, data_format: DataFormat::SevenEven }
+    }
+}
+
+impl Default for LineSettings {
+    fn default() -> LineSettings {
+        LineSettings::dsmr4()
+    }
+}
+
+// Reads from a serial device with DSMR line settings. When the device goes
+// away, for example when a USB adapter is unplugged, it is reopened with
+// backoff, so readers only see the gap as a truncated datagram.
+pub struct SerialSource {
+    path: PathBuf,
+    settings: LineSettings,
+    backoff: Backoff,
+    file: Option<File>,
+    reconnects: u64,
+}
+
+impl SerialSource {
+    // Opens the device right away, so configuration errors show up here.
+    pub fn open<P: AsRef<Path>>(path: P, settings: LineSettings) -> io::Result<SerialSource> {
+        let file = open_device(path.as_ref(), &settings)?;
+        Ok(SerialSource {
+            path: path.as_ref().to_path_buf(),
+            settings,
+            backoff: Backoff::default(),
+            file: Some(file),
+            reconnects: 0,
+        })
+    }
+
+    pub fn backoff(mut self, backoff: Backoff) -> SerialSource {
+        self.backoff = backoff;
+        self
+    }
+
+    pub fn into_reader(self) -> DatagramReader<io::BufReader<SerialSource>> {
+        DatagramReader::new(io::BufReader::new(self))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // The number of times the device was reopened.
+    pub fn reconnects(&self) -> u64 {
+        self.reconnects
+    }
+
+    fn reopen(&mut self) -> io::Result<()> {
+        let (path, settings) = (&self.path, &self.settings);
+        self.file = Some(self.backoff.retry(|| open_device(path, settings))?);
+        self.reconnects += 1;
+        Ok(())
+    }
+}
+
+impl Read for SerialSource {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if self.file.is_none() {
+                self.reopen()?;
+                return Err(Reconnected::error());
+            }
+            let result = match self.file {
+                Some(ref mut file) => file.read(buf),
+                None => continue,
+            };
+            match result {
+                // A terminal only reads end of file after a hangup.
+                Ok(0) if !buf.is_empty() => self.file = None,
+                Err(ref e) if e.kind() != io::ErrorKind::Interrupted => self.file = None,
+                result => return result,
+            }
+        }
+    }
+}
+
+fn open_device(path: &Path, settings: &LineSettings) -> io::Result<File> {
+    let speed = baud_rate_to_speed(settings.baud_rate)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "unsupported baud rate"))?;
+    let file = OpenOptions::new().read(true).write(true).custom_flags(libc::O_NOCTTY).open(path)?;
+    let fd = file.as_raw_fd();
+    unsafe {
+        let mut termios: libc::termios = std::mem::zeroed();
+        check(libc::tcgetattr(fd, &mut termios))?;
+        libc::cfmakeraw(&mut termios);
+        termios.c_cflag &= !(libc::CSIZE | libc::PARENB | libc::PARODD | libc::CSTOPB | libc::CRTSCTS);
+        termios.c_cflag |= libc::CLOCAL | libc::CREAD;
+        match settings.data_format {
+            DataFormat::EightNone => termios.c_cflag |= libc::CS8,
+            DataFormat::SevenEven => {
+                termios.c_cflag |= libc::CS7 | libc::PARENB;
+                termios.c_iflag |= libc::INPCK;
+            },
+        }
+        termios.c_cc[libc::VMIN] = 1;
+        termios.c_cc[libc::VTIME] = 0;
+        check(libc::cfsetispeed(&mut termios, speed))?;
+        check(libc::cfsetospeed(&mut termios, speed))?;
+        check(libc::tcsetattr(fd, libc::TCSANOW, &termios))?;
+        // Drop whatever arrived before the line was set up.
+        check(libc::tcflush(fd, libc::TCIFLUSH))?;
+    }
+    Ok(file)
+}
+
+fn check(result: libc::c_int) -> io::Result<()> {
+    if result == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+fn baud_rate_to_speed(baud_rate: u32) -> Option<libc::speed_t> {
+    match baud_rate {
+        1200 => Some(libc::B1200),
+        2400 => Some(libc::B2400),
+        4800 => Some(libc::B4800),
+        9600 => Some(libc::B9600),
+        19_200 => Some(libc::B19200),
+        38_400 => Some(libc::B38400),
+        57_600 => Some(libc::B57600),
+        115_200 => Some(libc::B115200),
+        230_400 => Some(libc::B230400),
+        _ => None,
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use std::ffi::CStr;
+    use std::fs;
+    use std::io::Write;
+    use std::os::unix::fs::symlink;
+    use std::os::unix::io::FromRawFd;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use super::super::ReadDatagram;
+
+    struct Pty {
+        master: File,
+        // Keeps the device node around until the source has opened it.
+        _slave: File,
+        path: PathBuf,
+    }
+
+    fn open_pty() -> Pty {
+        unsafe {
+            let mut master = 0;
+            let mut slave = 0;
+            assert_eq!(libc::openpty(&mut master, &mut slave, std::ptr::null_mut(), std::ptr::null(), std::ptr::null()), 0);
+            let mut name = [0 as libc::c_char; 64];
+            assert_eq!(libc::ptsname_r(master, name.as_mut_ptr(), name.len()), 0);
+            let path = PathBuf::from(CStr::from_ptr(name.as_ptr()).to_str().unwrap());
+            Pty { master: File::from_raw_fd(master), _slave: File::from_raw_fd(slave), path }
+        }
+    }
+
+    fn termios(file: &File) -> libc::termios {
+        unsafe {
+            let mut termios: libc::termios = std::mem::zeroed();
+            assert_eq!(libc::tcgetattr(file.as_raw_fd(), &mut termios), 0);
+            termios
+        }
+    }
+
+    #[test]
+    fn it_should_configure_the_line_settings() {
+        let pty = open_pty();
+
+        let source = SerialSource::open(&pty.path, LineSettings::dsmr2()).unwrap();
+
+        // Linux pseudo-terminals always use 8 bits without parity, so only
+        // the settings they keep can be checked here.
+        let termios = termios(source.file.as_ref().unwrap());
+        assert_eq!(termios.c_iflag & libc::INPCK, libc::INPCK);
+        assert_eq!(termios.c_lflag & libc::ICANON, 0);
+        assert_eq!(unsafe { libc::cfgetispeed(&termios) }, libc::B9600);
+    }
+
+    #[test]
+    fn it_should_read_datagrams_from_a_pseudo_terminal() {
+        let correct_datagram_1: &[u8] = include_bytes!("correct_datagram_1.test");
+        let mut pty = open_pty();
+        let mut reader = SerialSource::open(&pty.path, LineSettings::dsmr4()).unwrap().into_reader();
+
+        pty.master.write_all(correct_datagram_1).unwrap();
+        pty.master.write_all(b"\r\n").unwrap();
+
+        assert_eq!(reader.next().unwrap(), ReadDatagram::Datagram(correct_datagram_1.to_vec().into_boxed_slice()));
+    }
+
+    #[test]
+    fn it_should_reopen_the_device_when_it_disappears() {
+        let correct_datagram_1: &[u8] = include_bytes!("correct_datagram_1.test");
+        let link = std::env::temp_dir().join(format!("p1-serial-test-{}", std::process::id()));
+        let _ = fs::remove_file(&link);
+        let mut first = open_pty();
+        symlink(&first.path, &link).unwrap();
+        let backoff = Backoff { initial_delay: Duration::from_millis(10), max_delay: Duration::from_millis(50), max_attempts: Some(100) };
+        let mut reader = SerialSource::open(&link, LineSettings::dsmr4()).unwrap().backoff(backoff).into_reader().verify_crc(true);
+        first.master.write_all(correct_datagram_1).unwrap();
+        first.master.write_all(b"\r\n").unwrap();
+        assert_eq!(reader.next().unwrap(), ReadDatagram::Datagram(correct_datagram_1.to_vec().into_boxed_slice()));
+
+        let mut second = open_pty();
+        fs::remove_file(&link).unwrap();
+        symlink(&second.path, &link).unwrap();
+        let (sender, receiver) = mpsc::channel();
+        let thread = thread::spawn(move || {
+            let datagram = reader.find(|d| matches!(d, ReadDatagram::Datagram(_)));
+            sender.send(datagram).unwrap();
+            reader
+        });
+        drop(first);
+
+        // Keep sending until the source has reopened the device and set it up.
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let datagram = loop {
+            assert!(Instant::now() < deadline, "the device was not reopened");
+            second.master.write_all(correct_datagram_1).unwrap();
+            second.master.write_all(b"\r\n").unwrap();
+            if let Ok(datagram) = receiver.recv_timeout(Duration::from_millis(50)) {
+                break datagram;
+            }
+        };
+        let reader = thread.join().unwrap();
+        fs::remove_file(&link).unwrap();
+
+        assert_eq!(datagram.unwrap(), ReadDatagram::Datagram(correct_datagram_1.to_vec().into_boxed_slice()));
+        assert_eq!(reader.get_ref().get_ref().reconnects(), 1);
+    }
+}
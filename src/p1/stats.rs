@@ -0,0 +1,243 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+use super::ReadDatagram;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Counters {
+    pub datagrams: u64,
+    pub incomplete_datagrams: u64,
+    pub crc_failures: u64,
+    pub repaired_datagrams: u64,
+    pub oversized_datagrams: u64,
+    pub garbage_bytes: u64,
+    pub parity_errors: u64,
+    pub io_errors: u64,
+    pub bytes_read: u64,
+}
+
+impl Counters {
+    fn add(&mut self, other: &Counters) {
+        self.datagrams += other.datagrams;
+        self.incomplete_datagrams += other.incomplete_datagrams;
+        self.crc_failures += other.crc_failures;
+        self.repaired_datagrams += other.repaired_datagrams;
+        self.oversized_datagrams += other.oversized_datagrams;
+        self.garbage_bytes += other.garbage_bytes;
+        self.parity_errors += other.parity_errors;
+        self.io_errors += other.io_errors;
+        self.bytes_read += other.bytes_read;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Snapshot {
+    pub totals: Counters,
+    pub recent: Counters,
+    pub window: Duration,
+}
+
+impl Snapshot {
+    pub fn rate_per_minute<F: Fn(&Counters) -> u64>(&self, counter: F) -> f64 {
+        counter(&self.recent) as f64 * 60.0 / self.window.as_secs_f64()
+    }
+
+    // The fraction of recent complete datagrams that failed their CRC check.
+    pub fn crc_failure_ratio(&self) -> f64 {
+        if self.recent.datagrams == 0 {
+            0.0
+        } else {
+            self.recent.crc_failures as f64 / self.recent.datagrams as f64
+        }
+    }
+}
+
+// Keeps totals since creation, and per minute counters for the rolling
+// window of recent minutes.
+#[derive(Debug, Clone)]
+pub struct Statistics {
+    started: Instant,
+    window_minutes: u64,
+    totals: Counters,
+    minutes: VecDeque<(u64, Counters)>,
+}
+
+impl Statistics {
+    pub fn new(window: Duration) -> Statistics {
+        Statistics {
+            started: Instant::now(),
+            window_minutes: window.as_secs().div_ceil(60).max(1),
+            totals: Counters::default(),
+            minutes: VecDeque::new(),
+        }
+    }
+
+    // Counts a datagram as yielded by a DatagramReader.
+    pub fn record_datagram(&mut self, datagram: &ReadDatagram) {
+        self.record_datagram_at(Instant::now(), datagram);
+    }
+
+    // Counts the outcome of verify_crc or repair_crc on a datagram that was
+    // already counted by record_datagram.
+    pub fn record_crc(&mut self, datagram: &ReadDatagram) {
+        self.record_crc_at(Instant::now(), datagram);
+    }
+
+    pub fn record_garbage(&mut self, bytes: usize) {
+        self.update(Instant::now(), |c| c.garbage_bytes += bytes as u64);
+    }
+
+    pub fn record_oversized(&mut self) {
+        self.update(Instant::now(), |c| c.oversized_datagrams += 1);
+    }
+
+    pub fn record_parity_errors(&mut self, errors: u64) {
+        if errors > 0 {
+            self.update(Instant::now(), |c| c.parity_errors += errors);
+        }
+    }
+
+    pub fn record_io_error(&mut self) {
+        self.update(Instant::now(), |c| c.io_errors += 1);
+    }
+
+    pub fn record_bytes_read(&mut self, bytes: usize) {
+        self.update(Instant::now(), |c| c.bytes_read += bytes as u64);
+    }
+
+    pub fn totals(&self) -> Counters {
+        self.totals
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        self.snapshot_at(Instant::now())
+    }
+
+    fn record_datagram_at(&mut self, now: Instant, datagram: &ReadDatagram) {
+        match *datagram {
+            ReadDatagram::Datagram(_) => self.update(now, |c| c.datagrams += 1),
+            ReadDatagram::IncompleteDatagram(_) => self.update(now, |c| c.incomplete_datagrams += 1),
+            ReadDatagram::InvalidCrc { .. } => self.update(now, |c| {
+                c.datagrams += 1;
+                c.crc_failures += 1;
+            }),
+            ReadDatagram::Repaired { .. } => self.update(now, |c| {
+                c.datagrams += 1;
+                c.crc_failures += 1;
+                c.repaired_datagrams += 1;
+            }),
+        }
+    }
+
+    fn record_crc_at(&mut self, now: Instant, datagram: &ReadDatagram) {
+        match *datagram {
+            ReadDatagram::InvalidCrc { .. } => self.update(now, |c| c.crc_failures += 1),
+            ReadDatagram::Repaired { .. } => self.update(now, |c| {
+                c.crc_failures += 1;
+                c.repaired_datagrams += 1;
+            }),
+            _ => {},
+        }
+    }
+
+    fn update<F: Fn(&mut Counters)>(&mut self, now: Instant, update: F) {
+        update(&mut self.totals);
+        let minute = self.minute(now);
+        if self.minutes.back().map(|m| m.0) != Some(minute) {
+            self.minutes.push_back((minute, Counters::default()));
+        }
+        if let Some((_, counters)) = self.minutes.back_mut() {
+            update(counters);
+        }
+        while self.minutes.front().is_some_and(|m| m.0 + self.window_minutes <= minute) {
+            self.minutes.pop_front();
+        }
+    }
+
+    fn snapshot_at(&self, now: Instant) -> Snapshot {
+        let minute = self.minute(now);
+        let mut recent = Counters::default();
+        for (_, counters) in self.minutes.iter().filter(|m| m.0 + self.window_minutes > minute) {
+            recent.add(counters);
+        }
+        Snapshot {
+            totals: self.totals,
+            recent,
+            window: Duration::from_secs(self.window_minutes * 60),
+        }
+    }
+
+    fn minute(&self, now: Instant) -> u64 {
+        now.saturating_duration_since(self.started).as_secs() / 60
+    }
+}
+
+impl Default for Statistics {
+    fn default() -> Statistics {
+        Statistics::new(Duration::from_secs(15 * 60))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::CrcError;
+
+    fn invalid_crc() -> ReadDatagram {
+        ReadDatagram::InvalidCrc {
+            datagram: Box::new([]),
+            actual_crc: 0,
+            error: CrcError::Missing,
+        }
+    }
+
+    #[test]
+    fn it_should_count_datagrams_by_outcome() {
+        let mut statistics = Statistics::default();
+
+        statistics.record_datagram(&ReadDatagram::Datagram(Box::new([])));
+        statistics.record_datagram(&ReadDatagram::Datagram(Box::new([])));
+        statistics.record_crc(&invalid_crc());
+        statistics.record_datagram(&ReadDatagram::IncompleteDatagram(Box::new([])));
+        statistics.record_datagram(&ReadDatagram::Repaired { datagram: Box::new([]), corrected_offset: 0 });
+        statistics.record_oversized();
+        statistics.record_garbage(5);
+        statistics.record_parity_errors(2);
+        statistics.record_io_error();
+        statistics.record_bytes_read(100);
+
+        assert_eq!(statistics.totals(), Counters {
+            datagrams: 3,
+            incomplete_datagrams: 1,
+            crc_failures: 2,
+            repaired_datagrams: 1,
+            oversized_datagrams: 1,
+            garbage_bytes: 5,
+            parity_errors: 2,
+            io_errors: 1,
+            bytes_read: 100,
+        });
+    }
+
+    #[test]
+    fn it_should_only_include_the_window_in_recent_counters() {
+        let mut statistics = Statistics::new(Duration::from_secs(5 * 60));
+        let start = statistics.started;
+        let at = |minutes: u64| start + Duration::from_secs(minutes * 60 + 30);
+
+        statistics.record_datagram_at(at(0), &invalid_crc());
+        statistics.record_datagram_at(at(3), &ReadDatagram::Datagram(Box::new([])));
+        statistics.record_datagram_at(at(6), &invalid_crc());
+        statistics.record_datagram_at(at(6), &ReadDatagram::Datagram(Box::new([])));
+
+        let snapshot = statistics.snapshot_at(at(7));
+        assert_eq!(snapshot.totals.datagrams, 4);
+        assert_eq!(snapshot.recent.datagrams, 3);
+        assert_eq!(snapshot.recent.crc_failures, 1);
+        assert_eq!(snapshot.rate_per_minute(|c| c.datagrams), 0.6);
+        assert!((snapshot.crc_failure_ratio() - 1.0 / 3.0).abs() < 1e-9);
+
+        let snapshot = statistics.snapshot_at(at(20));
+        assert_eq!(snapshot.recent, Counters::default());
+        assert_eq!(statistics.minutes.len(), 2);
+    }
+}
@@ -0,0 +1,226 @@
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use bytes::{Buf, BytesMut};
+use futures_core::Stream;
+use tokio::io::AsyncBufRead;
+use tokio_util::codec::Decoder;
+use super::ReadDatagram;
+use super::decoder::P1Decoder;
+use super::reader::ReaderEvent;
+
+// The async counterpart of DatagramReader. It yields datagrams and skips
+// garbage and oversized datagrams. An I/O error is yielded as an item, after
+// which polling again resumes reading. The stream ends at the end of the
+// input.
+pub struct DatagramStream<R> {
+    reader: R,
+    decoder: P1Decoder,
+    done: bool,
+}
+
+impl<R: AsyncBufRead + Unpin> DatagramStream<R> {
+    pub fn new(reader: R) -> DatagramStream<R> {
+        DatagramStream::with_decoder(reader, P1Decoder::new())
+    }
+
+    pub fn with_decoder(reader: R, decoder: P1Decoder) -> DatagramStream<R> {
+        DatagramStream { reader, decoder, done: false }
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.reader
+    }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.reader
+    }
+
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+}
+
+impl<R: AsyncBufRead + Unpin> Stream for DatagramStream<R> {
+    type Item = io::Result<ReadDatagram>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        if this.done {
+            return Poll::Ready(None);
+        }
+        loop {
+            let (consumed, event) = match Pin::new(&mut this.reader).poll_fill_buf(cx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(Err(e)) => return Poll::Ready(Some(Err(e))),
+                Poll::Ready(Ok([])) => loop {
+                    match this.decoder.finish() {
+                        Some(ReaderEvent::Datagram(datagram, _)) => return Poll::Ready(Some(Ok(datagram))),
+                        Some(_) => {},
+                        None => {
+                            this.done = true;
+                            return Poll::Ready(None);
+                        },
+                    }
+                },
+                Poll::Ready(Ok(available)) => this.decoder.process(available),
+            };
+            Pin::new(&mut this.reader).consume(consumed);
+            if let Some(ReaderEvent::Datagram(datagram, _)) = event {
+                return Poll::Ready(Some(Ok(datagram)));
+            }
+        }
+    }
+}
+
+// Frames datagrams for tokio_util::codec::FramedRead. The decoder keeps the
+// partial datagram itself, so every call consumes all of the buffer.
+#[derive(Debug, Clone, Default)]
+pub struct P1Codec {
+    decoder: P1Decoder,
+}
+
+impl P1Codec {
+    pub fn new() -> P1Codec {
+        P1Codec::with_decoder(P1Decoder::new())
+    }
+
+    pub fn with_decoder(decoder: P1Decoder) -> P1Codec {
+        P1Codec { decoder }
+    }
+}
+
+impl Decoder for P1Codec {
+    type Item = ReadDatagram;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> io::Result<Option<ReadDatagram>> {
+        while !src.is_empty() {
+            let (consumed, event) = self.decoder.process(src);
+            src.advance(consumed);
+            if let Some(ReaderEvent::Datagram(datagram, _)) = event {
+                return Ok(Some(datagram));
+            }
+        }
+        Ok(None)
+    }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> io::Result<Option<ReadDatagram>> {
+        if let Some(datagram) = self.decode(src)? {
+            return Ok(Some(datagram));
+        }
+        loop {
+            match self.decoder.finish() {
+                Some(ReaderEvent::Datagram(datagram, _)) => return Ok(Some(datagram)),
+                Some(_) => {},
+                None => return Ok(None),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::task::Waker;
+    use tokio::io::{AsyncRead, ReadBuf};
+
+    // Hands out data in small chunks, and is only ready on every other poll.
+    struct TrickleReader<'a> {
+        data: &'a [u8],
+        chunk_size: usize,
+        ready: bool,
+    }
+
+    impl<'a> AsyncRead for TrickleReader<'a> {
+        fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+            let this = self.get_mut();
+            let length = match Pin::new(&mut *this).poll_fill_buf(cx) {
+                Poll::Ready(Ok(available)) => {
+                    let length = available.len().min(buf.remaining());
+                    buf.put_slice(&available[..length]);
+                    length
+                },
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            };
+            Pin::new(this).consume(length);
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    impl<'a> AsyncBufRead for TrickleReader<'a> {
+        fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<&[u8]>> {
+            let this = self.get_mut();
+            this.ready = !this.ready;
+            if this.ready {
+                Poll::Ready(Ok(&this.data[..this.chunk_size.min(this.data.len())]))
+            } else {
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
+        }
+
+        fn consume(self: Pin<&mut Self>, amount: usize) {
+            let this = self.get_mut();
+            this.data = &this.data[amount..];
+        }
+    }
+
+    fn collect<S: Stream + Unpin>(mut stream: S) -> Vec<S::Item> {
+        let mut context = Context::from_waker(Waker::noop());
+        let mut items = Vec::new();
+        loop {
+            match Pin::new(&mut stream).poll_next(&mut context) {
+                Poll::Ready(Some(item)) => items.push(item),
+                Poll::Ready(None) => return items,
+                Poll::Pending => {},
+            }
+        }
+    }
+
+    #[test]
+    fn it_should_stream_datagrams_from_an_async_reader() {
+        let correct_datagram_1: &[u8] = include_bytes!("correct_datagram_1.test");
+        let correct_datagram_2: &[u8] = include_bytes!("correct_datagram_2.test");
+        let mut combined_input: Vec<u8> = Vec::new();
+        combined_input.extend_from_slice(correct_datagram_1);
+        combined_input.extend_from_slice(&[4, 23, 32]);
+        combined_input.extend_from_slice(correct_datagram_2);
+        let reader = TrickleReader { data: combined_input.as_slice(), chunk_size: 7, ready: false };
+
+        let datagrams: Vec<ReadDatagram> = collect(DatagramStream::new(reader)).into_iter().map(|d| d.unwrap()).collect();
+
+        assert_eq!(datagrams, vec!(
+            ReadDatagram::Datagram(correct_datagram_1.to_vec().into_boxed_slice()),
+            ReadDatagram::Datagram(correct_datagram_2.to_vec().into_boxed_slice()),
+        ));
+    }
+
+    #[test]
+    fn it_should_decode_datagrams_with_the_codec() {
+        let correct_datagram_1: &[u8] = include_bytes!("correct_datagram_1.test");
+        let mut combined_input: Vec<u8> = Vec::new();
+        combined_input.extend_from_slice(correct_datagram_1);
+        combined_input.extend_from_slice(b"\r\n");
+        combined_input.extend_from_slice(&correct_datagram_1[..200]);
+        let mut codec = P1Codec::with_decoder(P1Decoder::new().verify_crc(true));
+        let mut buffer = BytesMut::new();
+        let mut datagrams = Vec::new();
+
+        for chunk in combined_input.chunks(100) {
+            buffer.extend_from_slice(chunk);
+            while let Some(datagram) = codec.decode(&mut buffer).unwrap() {
+                datagrams.push(datagram);
+            }
+        }
+        while let Some(datagram) = codec.decode_eof(&mut buffer).unwrap() {
+            datagrams.push(datagram);
+        }
+
+        assert_eq!(datagrams, vec!(
+            ReadDatagram::Datagram(correct_datagram_1.to_vec().into_boxed_slice()),
+            ReadDatagram::IncompleteDatagram(correct_datagram_1[..200].to_vec().into_boxed_slice()),
+        ));
+    }
+}
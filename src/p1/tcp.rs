@@ -0,0 +1,236 @@
+use std::io::{self, Read};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::time::Duration;
+#[cfg(unix)]
+use std::os::unix::io::AsRawFd;
+#[cfg(unix)]
+use libc;
+use super::reader::{Backoff, DatagramReader, Reconnected};
+
+// Reads raw P1 data from a TCP port, as served by ser2net or by Wi-Fi dongles
+// in raw mode. When the connection drops or stalls for longer than the read
+// timeout, it reconnects with backoff, so readers only see the gap as a
+// truncated datagram.
+pub struct TcpSource {
+    addresses: Vec<SocketAddr>,
+    connect_timeout: Duration,
+    read_timeout: Option<Duration>,
+    keepalive: Option<Duration>,
+    backoff: Backoff,
+    stream: Option<TcpStream>,
+    connected: bool,
+    reconnects: u64,
+}
+
+impl TcpSource {
+    // Resolves the address right away, but connects on the first read.
+    pub fn new<A: ToSocketAddrs>(address: A) -> io::Result<TcpSource> {
+        let addresses: Vec<SocketAddr> = address.to_socket_addrs()?.collect();
+        if addresses.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "address did not resolve"));
+        }
+        Ok(TcpSource {
+            addresses,
+            connect_timeout: Duration::from_secs(10),
+            // Meters send a telegram every 10 seconds at most.
+            read_timeout: Some(Duration::from_secs(60)),
+            keepalive: Some(Duration::from_secs(30)),
+            backoff: Backoff::default(),
+            stream: None,
+            connected: false,
+            reconnects: 0,
+        })
+    }
+
+    pub fn connect_timeout(mut self, connect_timeout: Duration) -> TcpSource {
+        self.connect_timeout = connect_timeout;
+        self
+    }
+
+    // A connection that delivers nothing for this long is considered stalled.
+    pub fn read_timeout(mut self, read_timeout: Option<Duration>) -> TcpSource {
+        self.read_timeout = read_timeout;
+        self
+    }
+
+    // Enables TCP keepalive with the given idle time. The idle time is only
+    // applied on Linux; other Unix systems use their default.
+    pub fn keepalive(mut self, keepalive: Option<Duration>) -> TcpSource {
+        self.keepalive = keepalive;
+        self
+    }
+
+    pub fn backoff(mut self, backoff: Backoff) -> TcpSource {
+        self.backoff = backoff;
+        self
+    }
+
+    pub fn into_reader(self) -> DatagramReader<io::BufReader<TcpSource>> {
+        DatagramReader::new(io::BufReader::new(self))
+    }
+
+    // The number of times the connection was made again after the first.
+    pub fn reconnects(&self) -> u64 {
+        self.reconnects
+    }
+
+    fn connect(&mut self) -> io::Result<()> {
+        self.stream = Some(self.backoff.retry(|| self.open())?);
+        if self.connected {
+            self.reconnects += 1;
+        }
+        self.connected = true;
+        Ok(())
+    }
+
+    fn open(&self) -> io::Result<TcpStream> {
+        let mut error = None;
+        for address in &self.addresses {
+            match TcpStream::connect_timeout(address, self.connect_timeout) {
+                Ok(stream) => {
+                    stream.set_read_timeout(self.read_timeout)?;
+                    if let Some(idle) = self.keepalive {
+                        set_keepalive(&stream, idle)?;
+                    }
+                    return Ok(stream);
+                },
+                Err(e) => error = Some(e),
+            }
+        }
+        Err(error.unwrap_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no addresses")))
+    }
+}
+
+impl Read for TcpSource {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if self.stream.is_none() {
+                let reconnect = self.connected;
+                self.connect()?;
+                if reconnect {
+                    return Err(Reconnected::error());
+                }
+            }
+            let result = match self.stream {
+                Some(ref mut stream) => stream.read(buf),
+                None => continue,
+            };
+            match result {
+                // The peer closed the connection.
+                Ok(0) if !buf.is_empty() => self.stream = None,
+                // A stall shows up as WouldBlock or TimedOut, depending on the
+                // platform, and is handled like any other broken connection.
+                Err(ref e) if e.kind() != io::ErrorKind::Interrupted => self.stream = None,
+                result => return result,
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+fn set_keepalive(stream: &TcpStream, idle: Duration) -> io::Result<()> {
+    let fd = stream.as_raw_fd();
+    set_option(fd, libc::SOL_SOCKET, libc::SO_KEEPALIVE, 1)?;
+    if cfg!(target_os = "linux") {
+        let seconds = idle.as_secs().clamp(1, libc::c_int::MAX as u64) as libc::c_int;
+        set_option(fd, libc::IPPROTO_TCP, libc::TCP_KEEPIDLE, seconds)?;
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn set_keepalive(_stream: &TcpStream, _idle: Duration) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(unix)]
+fn set_option(fd: libc::c_int, level: libc::c_int, name: libc::c_int, value: libc::c_int) -> io::Result<()> {
+    let result = unsafe {
+        libc::setsockopt(fd, level, name, &value as *const libc::c_int as *const libc::c_void, std::mem::size_of::<libc::c_int>() as libc::socklen_t)
+    };
+    if result == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::net::TcpListener;
+    use std::thread;
+    use super::super::ReadDatagram;
+
+    fn fast_backoff() -> Backoff {
+        Backoff { initial_delay: Duration::from_millis(1), max_delay: Duration::from_millis(10), max_attempts: Some(5) }
+    }
+
+    // Serves each list of chunks on its own connection. An empty chunk keeps
+    // the connection open without sending anything until the next connection
+    // has been served.
+    fn serve(connections: Vec<Vec<&'static [u8]>>) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut stalled = Vec::new();
+            for chunks in connections {
+                let (mut stream, _) = listener.accept().unwrap();
+                for chunk in &chunks {
+                    stream.write_all(chunk).unwrap();
+                }
+                if chunks.last().is_some_and(|c| c.is_empty()) {
+                    stalled.push(stream);
+                }
+            }
+            // Give the client a chance to read everything before closing.
+            thread::sleep(Duration::from_millis(200));
+        });
+        address
+    }
+
+    #[test]
+    fn it_should_read_datagrams_and_reconnect_when_the_connection_drops() {
+        let correct_datagram_1: &'static [u8] = include_bytes!("correct_datagram_1.test");
+        let correct_datagram_2: &'static [u8] = include_bytes!("correct_datagram_2.test");
+        let address = serve(vec!(vec!(correct_datagram_1, b"\r\n"), vec!(correct_datagram_2, b"\r\n")));
+        let mut reader = TcpSource::new(address).unwrap().backoff(fast_backoff()).into_reader();
+
+        assert_eq!(reader.next().unwrap(), ReadDatagram::Datagram(correct_datagram_1.to_vec().into_boxed_slice()));
+        assert_eq!(reader.next().unwrap(), ReadDatagram::Datagram(correct_datagram_2.to_vec().into_boxed_slice()));
+        assert_eq!(reader.get_ref().get_ref().reconnects(), 1);
+    }
+
+    #[test]
+    fn it_should_reconnect_when_the_connection_stalls() {
+        let correct_datagram_1: &'static [u8] = include_bytes!("correct_datagram_1.test");
+        let address = serve(vec!(vec!(&correct_datagram_1[..200], b""), vec!(correct_datagram_1, b"\r\n")));
+        let mut reader = TcpSource::new(address).unwrap()
+            .read_timeout(Some(Duration::from_millis(50)))
+            .backoff(fast_backoff())
+            .into_reader();
+
+        assert_eq!(reader.next().unwrap(), ReadDatagram::IncompleteDatagram(correct_datagram_1[..200].to_vec().into_boxed_slice()));
+        assert_eq!(reader.next().unwrap(), ReadDatagram::Datagram(correct_datagram_1.to_vec().into_boxed_slice()));
+    }
+
+    #[test]
+    fn it_should_not_join_a_datagram_across_a_reconnect() {
+        let correct_datagram_1: &'static [u8] = include_bytes!("correct_datagram_1.test");
+        let address = serve(vec!(vec!(&correct_datagram_1[..200]), vec!(&correct_datagram_1[200..], b"\r\n", correct_datagram_1, b"\r\n")));
+        let mut reader = TcpSource::new(address).unwrap().backoff(fast_backoff()).into_reader();
+
+        assert_eq!(reader.next().unwrap(), ReadDatagram::IncompleteDatagram(correct_datagram_1[..200].to_vec().into_boxed_slice()));
+        assert_eq!(reader.next().unwrap(), ReadDatagram::Datagram(correct_datagram_1.to_vec().into_boxed_slice()));
+    }
+
+    #[test]
+    fn it_should_give_up_after_the_last_attempt() {
+        let address = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let mut reader = TcpSource::new(address).unwrap().backoff(fast_backoff()).into_reader();
+
+        assert_eq!(reader.next(), None);
+        assert_eq!(reader.take_error().map(|e| e.kind()), Some(io::ErrorKind::ConnectionRefused));
+    }
+}
@@ -0,0 +1,545 @@
+use core::str;
+#[cfg(feature = "std")]
+use std::io::Write;
+use nom;
+use obis::{obis_identifier, ObisIdentifier};
+#[cfg(feature = "std")]
+use super::crc;
+
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, PartialEq)]
+pub struct Telegram {
+    pub identification: String,
+    pub objects: Vec<CosemObject>,
+}
+
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, PartialEq)]
+pub struct CosemObject {
+    pub id: ObisIdentifier,
+    // The identifier as it appeared in the datagram, such as with or without
+    // value group F, so it is written back the same way. It is ignored when
+    // it no longer matches id.
+    pub id_text: Option<String>,
+    pub values: Vec<Value>,
+}
+
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, PartialEq)]
+pub struct Value {
+    pub text: String,
+    pub unit: Option<String>,
+}
+
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub line: usize,
+    pub column: usize,
+    pub obis: Option<ObisIdentifier>,
+    pub bytes: Box<[u8]>,
+    pub reason: Reason,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reason {
+    MissingHeader,
+    MissingFooter,
+    MissingCarriageReturn,
+    InvalidObisIdentifier,
+    MissingValue,
+    UnexpectedCharacter,
+    UnterminatedValue,
+    InvalidText,
+}
+
+// Only Telegram::parse reports where a line went wrong.
+#[cfg_attr(not(feature = "std"), allow(dead_code))]
+struct LineError {
+    column: usize,
+    obis: Option<ObisIdentifier>,
+    start: usize,
+    end: usize,
+    reason: Reason,
+}
+
+impl LineError {
+    fn new(line: &[u8], obis: Option<ObisIdentifier>, start: usize, reason: Reason) -> LineError {
+        LineError { column: start + 1, obis, start, end: line.len(), reason }
+    }
+}
+
+#[cfg(feature = "std")]
+impl Telegram {
+    pub fn parse(datagram: &[u8]) -> (Telegram, Vec<Diagnostic>) {
+        let mut telegram = Telegram { identification: String::new(), objects: Vec::new() };
+        let mut diagnostics = Vec::new();
+        let mut footer_seen = false;
+        let mut lines = 0;
+
+        for (index, (line, has_cr)) in Lines::new(datagram).enumerate() {
+            let number = index + 1;
+            lines = number;
+
+            if index == 0 {
+                match parse_header(line) {
+                    Ok(identification) => telegram.identification = identification.to_owned(),
+                    Err(e) => diagnostics.push(diagnostic(number, line, e)),
+                }
+            } else if line.first() == Some(&b'!') {
+                footer_seen = true;
+                break;
+            } else if !line.is_empty() {
+                match parse_object(line) {
+                    Ok(object) => telegram.objects.push(object.to_object()),
+                    Err(e) => diagnostics.push(diagnostic(number, line, e)),
+                }
+            }
+
+            if !has_cr {
+                diagnostics.push(Diagnostic {
+                    line: number,
+                    column: line.len() + 1,
+                    obis: None,
+                    bytes: Box::new([]),
+                    reason: Reason::MissingCarriageReturn,
+                });
+            }
+        }
+
+        if !footer_seen {
+            diagnostics.push(Diagnostic {
+                line: lines + 1,
+                column: 1,
+                obis: None,
+                bytes: Box::new([]),
+                reason: Reason::MissingFooter,
+            });
+        }
+
+        (telegram, diagnostics)
+    }
+
+    pub fn object(&self, id: &ObisIdentifier) -> Option<&CosemObject> {
+        self.objects.iter().find(|o| o.id == *id)
+    }
+
+    // Writes the datagram in the form a DatagramReader yields it, up to and
+    // including the CRC.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut datagram = Vec::new();
+        write!(datagram, "/{}\r\n\r\n", self.identification).unwrap();
+        for object in &self.objects {
+            match object.id_text {
+                Some(ref text) if ObisIdentifier::parse(text).as_ref() == Some(&object.id) => datagram.extend_from_slice(text.as_bytes()),
+                _ => write_obis_identifier(&mut datagram, &object.id),
+            }
+            for value in &object.values {
+                match value.unit {
+                    Some(ref unit) => write!(datagram, "({}*{})", value.text, unit).unwrap(),
+                    None => write!(datagram, "({})", value.text).unwrap(),
+                }
+            }
+            datagram.extend_from_slice(b"\r\n");
+        }
+        datagram.push(b'!');
+        let crc = crc::checksum(&datagram);
+        write!(datagram, "{:04X}", crc).unwrap();
+        datagram
+    }
+
+    // Writes the datagram as a meter sends it, with the line end after the
+    // CRC, for writing telegrams one after another to a stream.
+    pub fn to_bytes_with_line_end(&self) -> Vec<u8> {
+        let mut datagram = self.to_bytes();
+        datagram.extend_from_slice(b"\r\n");
+        datagram
+    }
+}
+
+#[cfg(feature = "std")]
+// Telegrams leave out value group F when it has its default value.
+fn write_obis_identifier(datagram: &mut Vec<u8>, id: &ObisIdentifier) {
+    if let Some(a) = id.a() {
+        write!(datagram, "{}-", a).unwrap();
+    }
+    if let Some(b) = id.b() {
+        write!(datagram, "{}:", b).unwrap();
+    }
+    write!(datagram, "{}.{}.{}", id.c(), id.d(), id.e()).unwrap();
+    if id.f() != 255 {
+        write!(datagram, ".{}", id.f()).unwrap();
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TelegramRef<'a> {
+    datagram: &'a [u8],
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CosemObjectRef<'a> {
+    pub id: ObisIdentifier,
+    id_text: &'a [u8],
+    values: &'a [u8],
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ValueRef<'a> {
+    pub text: &'a str,
+    pub unit: Option<&'a str>,
+}
+
+impl<'a> TelegramRef<'a> {
+    pub fn new(datagram: &'a [u8]) -> TelegramRef<'a> {
+        TelegramRef { datagram }
+    }
+
+    pub fn identification(&self) -> Option<&'a str> {
+        Lines::new(self.datagram).next().and_then(|(line, _)| parse_header(line).ok())
+    }
+
+    pub fn objects(&self) -> Objects<'a> {
+        let mut lines = Lines::new(self.datagram);
+        lines.next();
+        Objects { lines }
+    }
+
+    pub fn object(&self, id: &ObisIdentifier) -> Option<CosemObjectRef<'a>> {
+        self.objects().find(|o| o.id == *id)
+    }
+}
+
+#[cfg(feature = "std")]
+impl<'a> TelegramRef<'a> {
+    pub fn to_telegram(&self) -> Telegram {
+        Telegram {
+            identification: self.identification().unwrap_or("").to_owned(),
+            objects: self.objects().map(|o| o.to_object()).collect(),
+        }
+    }
+}
+
+impl<'a> CosemObjectRef<'a> {
+    pub fn values(&self) -> ValuesRef<'a> {
+        ValuesRef { values: self.values }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<'a> CosemObjectRef<'a> {
+    pub fn to_object(&self) -> CosemObject {
+        CosemObject {
+            id: self.id.clone(),
+            id_text: str::from_utf8(self.id_text).ok().map(|t| t.to_owned()),
+            values: self.values().map(|v| v.to_value()).collect(),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<'a> ValueRef<'a> {
+    pub fn to_value(&self) -> Value {
+        Value {
+            text: self.text.to_owned(),
+            unit: self.unit.map(|u| u.to_owned()),
+        }
+    }
+}
+
+// Yields the valid objects of a telegram, skipping malformed lines. Use
+// Telegram::parse to find out why a line was skipped.
+pub struct Objects<'a> {
+    lines: Lines<'a>,
+}
+
+impl<'a> Iterator for Objects<'a> {
+    type Item = CosemObjectRef<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        for (line, _) in &mut self.lines {
+            if line.first() == Some(&b'!') {
+                break;
+            }
+            if let Ok(object) = parse_object(line) {
+                return Some(object);
+            }
+        }
+        None
+    }
+}
+
+// Iterates the values of an object whose line was already validated by
+// parse_object.
+pub struct ValuesRef<'a> {
+    values: &'a [u8],
+}
+
+impl<'a> Iterator for ValuesRef<'a> {
+    type Item = ValueRef<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let end = self.values.iter().position(|b| *b == b')')?;
+        let value = str::from_utf8(&self.values[1..end]).unwrap_or("");
+        self.values = &self.values[end + 1..];
+        Some(split_unit(value))
+    }
+}
+
+#[cfg(feature = "std")]
+fn diagnostic(line_number: usize, line: &[u8], e: LineError) -> Diagnostic {
+    Diagnostic {
+        line: line_number,
+        column: e.column,
+        obis: e.obis,
+        bytes: line[e.start..e.end].to_vec().into_boxed_slice(),
+        reason: e.reason,
+    }
+}
+
+// Splits on LF and strips the CR before it, telling whether there was one.
+// The text after the last LF is only a line if it is not empty.
+struct Lines<'a> {
+    remaining: Option<&'a [u8]>,
+}
+
+impl<'a> Lines<'a> {
+    fn new(datagram: &'a [u8]) -> Lines<'a> {
+        Lines { remaining: Some(datagram) }
+    }
+}
+
+impl<'a> Iterator for Lines<'a> {
+    type Item = (&'a [u8], bool);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let remaining = self.remaining?;
+        let line = match remaining.iter().position(|b| *b == b'\n') {
+            Some(lf) => {
+                self.remaining = Some(&remaining[lf + 1..]).filter(|r| !r.is_empty());
+                &remaining[..lf]
+            },
+            None if remaining.is_empty() => return None,
+            None => {
+                self.remaining = None;
+                remaining
+            },
+        };
+        match line.last() {
+            Some(b'\r') => Some((&line[..line.len() - 1], true)),
+            _ => Some((line, false)),
+        }
+    }
+}
+
+fn parse_header(line: &[u8]) -> Result<&str, LineError> {
+    match line.first() {
+        Some(b'/') => str::from_utf8(&line[1..]).map_err(|_| LineError::new(line, None, 1, Reason::InvalidText)),
+        _ => Err(LineError::new(line, None, 0, Reason::MissingHeader)),
+    }
+}
+
+fn parse_object(line: &[u8]) -> Result<CosemObjectRef<'_>, LineError> {
+    let (rest, id) = match obis_identifier(line) {
+        Ok(result) => result,
+        Err(nom::Err::Incomplete(_)) => return Err(LineError::new(line, None, 0, Reason::MissingValue)),
+        Err(_) => return Err(LineError::new(line, None, 0, Reason::InvalidObisIdentifier)),
+    };
+
+    let start = line.len() - rest.len();
+    if start == line.len() {
+        return Err(LineError::new(line, Some(id), start, Reason::MissingValue));
+    }
+    let mut offset = start;
+    while offset < line.len() {
+        if line[offset] != b'(' {
+            return Err(LineError::new(line, Some(id), offset, Reason::UnexpectedCharacter));
+        }
+        let end = match line[offset..].iter().position(|b| *b == b')') {
+            Some(end) => offset + end,
+            None => return Err(LineError::new(line, Some(id), offset, Reason::UnterminatedValue)),
+        };
+        if str::from_utf8(&line[offset + 1..end]).is_err() {
+            let mut e = LineError::new(line, Some(id), offset, Reason::InvalidText);
+            e.end = end + 1;
+            return Err(e);
+        }
+        offset = end + 1;
+    }
+    Ok(CosemObjectRef { id, id_text: &line[..start], values: &line[start..] })
+}
+
+fn split_unit(value: &str) -> ValueRef<'_> {
+    match value.rfind('*') {
+        Some(star) => ValueRef { text: &value[..star], unit: Some(&value[star + 1..]) },
+        None => ValueRef { text: value, unit: None },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(feature = "std")]
+    use std::io;
+    #[cfg(feature = "std")]
+    use super::super::{verify_crc, ReadDatagram};
+    #[cfg(feature = "std")]
+    use super::super::reader::DatagramReader;
+
+    fn id(id: &str) -> ObisIdentifier {
+        ObisIdentifier::parse(id).unwrap()
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn it_should_parse_a_correct_datagram_without_diagnostics() {
+        let correct_datagram_1: &[u8] = include_bytes!("correct_datagram_1.test");
+
+        let (telegram, diagnostics) = Telegram::parse(correct_datagram_1);
+
+        assert_eq!(diagnostics, vec![]);
+        assert_eq!(telegram.identification, "ISk5\\2MT382-1000");
+        assert_eq!(telegram.objects.len(), 35);
+        assert_eq!(telegram.object(&id("1-0:1.8.1")).unwrap().values, vec![
+            Value { text: "123456.789".to_owned(), unit: Some("kWh".to_owned()) },
+        ]);
+        assert_eq!(telegram.object(&id("1-0:99.97.0")).unwrap().values.len(), 6);
+        assert_eq!(telegram.object(&id("0-1:24.2.1")).unwrap().values[1].unit, Some("m3".to_owned()));
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn it_should_write_a_parsed_datagram_back_byte_for_byte() {
+        let correct_datagram_1: &[u8] = include_bytes!("correct_datagram_1.test");
+        let correct_datagram_2: &[u8] = include_bytes!("correct_datagram_2.test");
+
+        assert_eq!(Telegram::parse(correct_datagram_1).0.to_bytes(), correct_datagram_1);
+        assert_eq!(Telegram::parse(correct_datagram_2).0.to_bytes(), correct_datagram_2);
+        let explicit_f = b"/X\r\n\r\n1-0:1.8.1.255(000001.000*kWh)\r\n1-0:1.8.2(000002.000*kWh)\r\n!";
+        let written = Telegram::parse(explicit_f).0.to_bytes();
+        assert_eq!(&written[..written.len() - 4], &explicit_f[..]);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn it_should_write_a_datagram_a_reader_accepts_at_the_end_of_a_stream() {
+        let correct_datagram_1: &[u8] = include_bytes!("correct_datagram_1.test");
+        let datagram = Telegram::parse(correct_datagram_1).0.to_bytes_with_line_end();
+
+        let mut reader = DatagramReader::new(io::BufReader::new(&datagram[..])).verify_crc(true);
+
+        assert_eq!(reader.next().unwrap(), ReadDatagram::Datagram(correct_datagram_1.to_vec().into_boxed_slice()));
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn it_should_write_an_edited_telegram_with_a_valid_crc() {
+        let correct_datagram_1: &[u8] = include_bytes!("correct_datagram_1.test");
+        let (mut telegram, _) = Telegram::parse(correct_datagram_1);
+        telegram.objects[3].values[0].text = "000001.000".to_owned();
+
+        let datagram = telegram.to_bytes();
+
+        assert!(datagram.ends_with(b"\r\n0-1:24.2.1(101209112500W)(12785.123*m3)\r\n!87F7"));
+        let datagram = datagram.into_boxed_slice();
+        assert_eq!(verify_crc(ReadDatagram::Datagram(datagram.clone())), ReadDatagram::Datagram(datagram));
+    }
+
+    #[test]
+    fn it_should_borrow_objects_and_values_from_the_datagram() {
+        let correct_datagram_1: &[u8] = include_bytes!("correct_datagram_1.test");
+
+        let telegram = TelegramRef::new(correct_datagram_1);
+
+        assert_eq!(telegram.identification(), Some("ISk5\\2MT382-1000"));
+        assert_eq!(telegram.objects().count(), 35);
+        let mut values = telegram.object(&id("0-1:24.2.1")).unwrap().values();
+        assert_eq!(values.next(), Some(ValueRef { text: "101209112500W", unit: None }));
+        assert_eq!(values.next(), Some(ValueRef { text: "12785.123", unit: Some("m3") }));
+        assert_eq!(values.next(), None);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn it_should_skip_malformed_lines_in_a_borrowed_telegram() {
+        let datagram = b"/ISk5\\2MT382-1000\r\n\r\n1-0:x.8.1(1*kWh)\r\n1-0:1.8.2(2*kWh)\r\n!";
+
+        let telegram = TelegramRef::new(datagram);
+
+        assert_eq!(telegram.objects().map(|o| o.id).collect::<Vec<_>>(), vec![id("1-0:1.8.2")]);
+        assert_eq!(telegram.to_telegram(), Telegram::parse(datagram).0);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn it_should_keep_valid_objects_when_a_line_is_malformed() {
+        let correct_datagram_1: &[u8] = include_bytes!("correct_datagram_1.test");
+        let mut datagram = correct_datagram_1.to_vec();
+        let start = datagram.windows(9).position(|w| w == b"1-0:1.8.2").unwrap();
+        datagram[start + 9] = b'[';
+
+        let (telegram, diagnostics) = Telegram::parse(&datagram);
+
+        assert_eq!(telegram.objects.len(), 34);
+        assert_eq!(telegram.object(&id("1-0:1.8.2")), None);
+        assert_eq!(diagnostics, vec![Diagnostic {
+            line: 7,
+            column: 10,
+            obis: Some(id("1-0:1.8.2")),
+            bytes: b"[123456.789*kWh)".to_vec().into_boxed_slice(),
+            reason: Reason::UnexpectedCharacter,
+        }]);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn it_should_report_an_invalid_obis_identifier() {
+        let (telegram, diagnostics) = Telegram::parse(b"/ISk5\\2MT382-1000\r\n\r\n1-0:x.8.1(1*kWh)\r\n1-0:1.8.2(2*kWh)\r\n!");
+
+        assert_eq!(telegram.objects.len(), 1);
+        assert_eq!(diagnostics, vec![Diagnostic {
+            line: 3,
+            column: 1,
+            obis: None,
+            bytes: b"1-0:x.8.1(1*kWh)".to_vec().into_boxed_slice(),
+            reason: Reason::InvalidObisIdentifier,
+        }]);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn it_should_report_a_value_group_above_255_as_an_invalid_obis_identifier() {
+        let (telegram, diagnostics) = Telegram::parse(b"/X\r\n\r\n1-0:999.8.1(1)\r\n!");
+
+        assert!(telegram.objects.is_empty());
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].reason, Reason::InvalidObisIdentifier);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn it_should_report_an_unterminated_value() {
+        let (_, diagnostics) = Telegram::parse(b"/ISk5\\2MT382-1000\r\n\r\n1-0:1.8.1(1*kWh)(2\r\n!");
+
+        assert_eq!(diagnostics, vec![Diagnostic {
+            line: 3,
+            column: 17,
+            obis: Some(id("1-0:1.8.1")),
+            bytes: b"(2".to_vec().into_boxed_slice(),
+            reason: Reason::UnterminatedValue,
+        }]);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn it_should_report_a_missing_header_footer_and_carriage_return() {
+        let (telegram, diagnostics) = Telegram::parse(b"ISk5\r\n1-0:1.8.1(1*kWh)\n");
+
+        assert_eq!(telegram.objects.len(), 1);
+        assert_eq!(diagnostics.iter().map(|d| (d.line, d.reason)).collect::<Vec<_>>(), vec![
+            (1, Reason::MissingHeader),
+            (2, Reason::MissingCarriageReturn),
+            (3, Reason::MissingFooter),
+        ]);
+    }
+}
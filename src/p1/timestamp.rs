@@ -0,0 +1,32 @@
+use std::time::{Instant, SystemTime};
+
+// The host side receive time of a byte. The instant is for measuring
+// intervals, as the wall clock may jump; the wall clock is for relating it
+// to other data, as meter clocks drift and only have one second resolution.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timestamp {
+    pub instant: Instant,
+    pub time: SystemTime,
+}
+
+impl Timestamp {
+    pub fn now() -> Timestamp {
+        Timestamp { instant: Instant::now(), time: SystemTime::now() }
+    }
+}
+
+// When a datagram arrived. The times are taken when the bytes are decoded,
+// which for a reader is right after they were read, so they are as precise as
+// the chunks the source delivers. An incomplete datagram may have ended before
+// its CRC byte, the '!', arrived.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Received {
+    pub first_byte: Timestamp,
+    pub crc_byte: Option<Timestamp>,
+}
+
+impl Received {
+    pub fn last_byte(&self) -> Timestamp {
+        self.crc_byte.unwrap_or(self.first_byte)
+    }
+}